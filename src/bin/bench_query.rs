@@ -0,0 +1,95 @@
+use hamt_rs::{
+    cache::{CacheLimit, NodeCache},
+    query::RootMapBlock,
+    source::{BlockSource, Delayed},
+    Cid,
+};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Cli {
+    block_db: PathBuf,
+    tree_db: PathBuf,
+    root: Cid,
+    /// Number of keys to look up
+    #[structopt(long, default_value = "100")]
+    lookups: usize,
+    /// Latency added to every block fetch, in milliseconds
+    #[structopt(long, default_value = "50")]
+    delay_ms: u64,
+    /// Maximum number of speculative fetches in flight
+    #[structopt(long, default_value = "16")]
+    concurrency: usize,
+    /// Maximum number of children prefetched for every node a lookup visits
+    #[structopt(long, default_value = "4")]
+    fanout: usize,
+    /// Maximum number of nodes in the cache
+    #[structopt(long, default_value = "100000")]
+    cache_entries: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Cli::from_args();
+
+    let db = sled::open(args.block_db).unwrap();
+    let hash_keycid = db.open_tree("hash_keycid").unwrap();
+
+    // Spread the lookups over the whole tree
+    let step = (hash_keycid.len() / args.lookups.max(1)).max(1);
+    let keys: Vec<(Vec<u8>, Cid)> = hash_keycid
+        .iter()
+        .step_by(step)
+        .take(args.lookups)
+        .map(|entry| {
+            let keycid = entry.unwrap().1;
+            let (key, cid): (&[u8], &[u8]) = bincode::deserialize(&keycid).unwrap();
+            (key.to_vec(), Cid::try_from(cid).unwrap())
+        })
+        .collect();
+
+    let cid_tree = sled::open(args.tree_db).unwrap();
+    let source: Arc<dyn BlockSource> = Arc::new(Delayed::new(
+        (*cid_tree).clone(),
+        Duration::from_millis(args.delay_ms),
+    ));
+
+    let root_block = source.get(&args.root).await.unwrap();
+
+    for prefetch in [false, true] {
+        let cache = Arc::new(NodeCache::new(CacheLimit::Entries(args.cache_entries)));
+        let mut root: RootMapBlock = minicbor::decode(&root_block).unwrap();
+        root = root
+            .with_source(Arc::clone(&source))
+            .with_cache(Arc::clone(&cache));
+
+        // Both variants start from an empty cache, and warming it counts towards the total
+        let now = Instant::now();
+        if prefetch {
+            root = root.with_prefetch(args.concurrency, args.fanout);
+
+            root.warm_cache().await.unwrap();
+            println!("Warmed cache: {:.2?}", now.elapsed());
+        }
+
+        for (key, cid) in keys.iter() {
+            assert_eq!(root.get_key(key).await.unwrap().as_ref(), Some(cid));
+        }
+        let elapsed = now.elapsed();
+
+        println!(
+            "Prefetch: {} Lookups: {} Elapsed: {:.2?} Per lookup: {:.2?} Hits: {} Misses: {}",
+            prefetch,
+            keys.len(),
+            elapsed,
+            elapsed / keys.len().max(1) as u32,
+            cache.hits(),
+            cache.misses()
+        );
+    }
+}
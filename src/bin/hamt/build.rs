@@ -0,0 +1,122 @@
+use crate::config::{info, Config, TreeOptions};
+use anyhow::{bail, Context, Result};
+use hamt_rs::{save_root, Cid, IpldHashMap};
+use indicatif::ProgressIterator;
+use rayon::prelude::*;
+use std::time::Instant;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct BuildArgs {
+    #[structopt(flatten)]
+    options: TreeOptions,
+}
+
+pub fn run(config: &Config, args: BuildArgs) -> Result<()> {
+    build(config, &args.options).map(|_| ())
+}
+
+/// Build the tree and record its root in the tree db, returning the root CID
+pub fn build(config: &Config, options: &TreeOptions) -> Result<Cid> {
+    let db = config.open_block_db()?;
+    let hash_keycid = db.open_tree("hash_keycid")?;
+
+    let cid_tree = config.open_tree_db()?;
+    cid_tree.clear()?;
+
+    let now = Instant::now();
+    let (cid, count) = match options.single_threaded {
+        true => build_single(config, options, &hash_keycid, &cid_tree)?,
+        false => build_parallel(config, options, &hash_keycid, &cid_tree)?,
+    };
+    save_root(&cid_tree, &cid)?;
+    cid_tree.flush()?;
+
+    info!(config, "Elapsed: {:.2?}", now.elapsed());
+    println!("Root CID: {} Count: {}", cid, count);
+    Ok(cid)
+}
+
+fn build_single(
+    config: &Config,
+    options: &TreeOptions,
+    hash_keycid: &sled::Tree,
+    cid_tree: &sled::Tree,
+) -> Result<(Cid, i64)> {
+    let mut tree = IpldHashMap::new(options.width.into(), options.bucket_size.into());
+    let mut count: i64 = 0;
+
+    let progress = config.progress(Some(hash_keycid.len() as u64));
+    for hash_keycid in hash_keycid.iter().progress_with(progress.clone()) {
+        let hash_keycid = hash_keycid?.1;
+        let (key, cid): (&[u8], &[u8]) = bincode::deserialize(&hash_keycid)?;
+        tree.set(Vec::from(key).into_boxed_slice(), Cid::try_from(cid)?)?;
+        count += 1;
+    }
+    progress.finish_and_clear();
+
+    Ok((tree.collapse(cid_tree), count))
+}
+
+fn build_parallel(
+    config: &Config,
+    options: &TreeOptions,
+    hash_keycid: &sled::Tree,
+    cid_tree: &sled::Tree,
+) -> Result<(Cid, i64)> {
+    let width = options.width;
+    // Each subtree is found by a prefix of the first byte of the key hash
+    if !(1..8).contains(&width) {
+        bail!(
+            "A width of {} cannot be built in parallel, pass --single-threaded",
+            width
+        );
+    }
+
+    let tree = IpldHashMap::new(width.into(), options.bucket_size.into());
+    let buckets = 2u8.pow(width.into());
+    let iterations_per_prefix = 2u8.pow((8 - width).into());
+
+    info!(
+        config,
+        "Starting insert! {} buckets {} iterations", buckets, iterations_per_prefix
+    );
+
+    // Build up subtrees in parallel
+    let iterator: Vec<u8> = (0..buckets).map(|x| x << (8 - width)).collect();
+    let progress = config.progress(Some(iterator.len() as u64));
+
+    let subtree_cids: Vec<(i64, Cid)> = iterator
+        .par_iter()
+        .map(|prefix| -> Result<(i64, Cid)> {
+            let mut tree = IpldHashMap::new(width.into(), options.bucket_size.into());
+            let mut count = 0;
+
+            for iteration in 0..iterations_per_prefix {
+                let scan_prefix: &[u8] = &[prefix + iteration];
+
+                for key_cid in hash_keycid.scan_prefix(scan_prefix) {
+                    let key_cid = key_cid?.1;
+                    let (key, cid): (&[u8], &[u8]) = bincode::deserialize(&key_cid)?;
+                    tree.set(Vec::from(key).into_boxed_slice(), Cid::try_from(cid)?)?;
+                    count += 1;
+                }
+            }
+
+            let cid = tree
+                .collapse_partial(cid_tree)
+                .context("Could not build a subtree, pass --single-threaded for small HAMTs")?;
+            progress.inc(1);
+
+            Ok((count, cid))
+        })
+        .collect::<Result<_>>()?;
+    progress.finish_and_clear();
+
+    // Combine subtrees into a single tree
+    let total: i64 = subtree_cids.iter().map(|(c, _)| c).sum();
+    let subtree_cids = subtree_cids.into_iter().map(|(_, c)| c).collect();
+
+    let cid = tree.serialize_root_of_subtrees(cid_tree, subtree_cids)?;
+    Ok((cid, total))
+}
@@ -0,0 +1,187 @@
+use anyhow::Result;
+use cid::Cid;
+use hamt_rs::car::{BlockWriter, Car, CarV2, SeenSet, SplitCar, SplitLimit};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+// Options shared by every subcommand
+#[derive(StructOpt)]
+pub struct Config {
+    /// Sled db holding the key hash to record CID index written by ingest
+    #[structopt(long, global = true, default_value = "blocks.db")]
+    pub block_db: PathBuf,
+    /// Sled db holding the tree nodes and root CID written by build
+    #[structopt(long, global = true, default_value = "tree.db")]
+    pub tree_db: PathBuf,
+    /// Number of worker threads, one per core by default
+    #[structopt(long, global = true)]
+    pub threads: Option<usize>,
+    /// Only print results, without status messages or progress bars
+    #[structopt(short, long, global = true)]
+    pub quiet: bool,
+}
+
+impl Config {
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(num_cpus::get).max(1)
+    }
+
+    pub fn open_block_db(&self) -> Result<sled::Db> {
+        Ok(sled::Config::new()
+            .path(&self.block_db)
+            .flush_every_ms(Some(5000))
+            .mode(sled::Mode::HighThroughput)
+            .open()?)
+    }
+
+    pub fn open_tree_db(&self) -> Result<sled::Db> {
+        Ok(sled::Config::new()
+            .path(&self.tree_db)
+            .flush_every_ms(Some(1000))
+            .mode(sled::Mode::HighThroughput)
+            .open()?)
+    }
+
+    /// A progress bar over `len` items, or a counter if the length is unknown
+    pub fn progress(&self, len: Option<u64>) -> ProgressBar {
+        if self.quiet {
+            return ProgressBar::hidden();
+        }
+        match len {
+            Some(len) => ProgressBar::new(len),
+            None => ProgressBar::new_spinner()
+                .with_style(ProgressStyle::default_spinner().template("{spinner} {pos} {per_sec}")),
+        }
+    }
+}
+
+/// Print a status message to stderr unless `--quiet` was given, results go to stdout
+macro_rules! info {
+    ($config:expr, $($arg:tt)*) => {
+        if !$config.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+// Shape of the HAMT to build
+#[derive(StructOpt)]
+pub struct TreeOptions {
+    /// Number of bits of the key hash used at each level, so each node has 2^width children
+    #[structopt(long, default_value = "4")]
+    pub width: u8,
+    /// Maximum number of entries in a bucket before it is split into a node
+    #[structopt(long, default_value = "3")]
+    pub bucket_size: u8,
+    /// Build on a single thread, needed for small HAMTs of less than a thousand or so entries
+    #[structopt(long)]
+    pub single_threaded: bool,
+}
+
+// How cars are written
+#[derive(StructOpt)]
+pub struct CarOptions {
+    /// Write a CARv2 with an index of every block
+    #[structopt(long)]
+    pub v2: bool,
+    /// Split the output into volumes of at most this many bytes
+    #[structopt(long, conflicts_with = "v2")]
+    pub split_bytes: Option<u64>,
+    /// Split the output into volumes of at most this many blocks
+    #[structopt(long, conflicts_with = "v2")]
+    pub split_blocks: Option<u64>,
+    /// Skip blocks that were already written, tracking CIDs in memory or on disk
+    #[structopt(long)]
+    pub dedup: Option<DedupMode>,
+}
+
+pub enum DedupMode {
+    Memory,
+    Disk,
+}
+
+impl FromStr for DedupMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(DedupMode::Memory),
+            "disk" => Ok(DedupMode::Disk),
+            _ => Err(format!("Unknown dedup mode {}, expected memory or disk", s)),
+        }
+    }
+}
+
+impl CarOptions {
+    /// Create the car at `path`, with its header already written
+    pub fn writer(&self, path: &Path, roots: Vec<Cid>) -> Result<Box<dyn BlockWriter + Send>> {
+        let dedup = match self.dedup {
+            Some(DedupMode::Memory) => Some(SeenSet::memory()),
+            Some(DedupMode::Disk) => {
+                let seen = sled::Config::new().temporary(true).open()?;
+                Some(SeenSet::disk((*seen).clone()))
+            }
+            None => None,
+        };
+
+        let mut car: Box<dyn BlockWriter + Send> =
+            if self.split_bytes.is_some() || self.split_blocks.is_some() {
+                let limit = SplitLimit {
+                    bytes: self.split_bytes,
+                    blocks: self.split_blocks,
+                };
+                let car = SplitCar::new(path, roots, limit);
+                match dedup {
+                    Some(seen) => Box::new(car.with_dedup(seen)),
+                    None => Box::new(car),
+                }
+            } else {
+                let file = BufWriter::with_capacity(128 * 1024, File::create(path)?);
+                match (self.v2, dedup) {
+                    (true, Some(seen)) => Box::new(CarV2::new(file, roots)?.with_dedup(seen)),
+                    (true, None) => Box::new(CarV2::new(file, roots)?),
+                    (false, Some(seen)) => Box::new(Car::new(file, roots).with_dedup(seen)),
+                    (false, None) => Box::new(Car::new(file, roots)),
+                }
+            };
+        car.encode_header()?;
+        Ok(car)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamt_rs::car::CarReader;
+    use multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn writer_writes_the_header_and_dedups() {
+        let path = std::env::temp_dir().join(format!("hamt-writer-{}.car", std::process::id()));
+        let options = CarOptions::from_iter_safe(["car", "--dedup", "memory"]).unwrap();
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"block"));
+
+        let mut car = options.writer(&path, vec![cid]).unwrap();
+        car.write_block(&cid.to_bytes(), b"block").unwrap();
+        car.write_block(&cid.to_bytes(), b"block").unwrap();
+        assert_eq!(car.duplicates(), 1);
+        car.finish().unwrap();
+
+        let mut reader = CarReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.roots(), [cid]);
+        let blocks = reader
+            .blocks()
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(blocks, vec![(cid, b"block".to_vec())]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
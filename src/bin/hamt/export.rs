@@ -0,0 +1,72 @@
+use crate::config::{info, CarOptions, Config};
+use anyhow::{anyhow, Result};
+use hamt_rs::{car::write_tree_depth_first, load_root, Cid};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct ExportArgs {
+    car: PathBuf,
+    /// Walk the tree from the root and write blocks in depth-first order, instead of
+    /// CID order, so the car can be verified while it is streamed
+    #[structopt(long)]
+    depth_first: bool,
+    /// Root CID printed by the build step, read from the tree db if not given
+    #[structopt(long)]
+    root: Option<Cid>,
+    #[structopt(flatten)]
+    car_options: CarOptions,
+}
+
+pub fn run(config: &Config, args: ExportArgs) -> Result<()> {
+    export_tree(
+        config,
+        &args.car,
+        args.root,
+        args.depth_first,
+        &args.car_options,
+    )
+}
+
+/// Write the tree db to `path`, with `root` or the root recorded by build in the header
+pub fn export_tree(
+    config: &Config,
+    path: &Path,
+    root: Option<Cid>,
+    depth_first: bool,
+    car_options: &CarOptions,
+) -> Result<()> {
+    let cid_tree = config.open_tree_db()?;
+
+    let root = match root {
+        Some(root) => root,
+        None => load_root(&cid_tree)?
+            .ok_or_else(|| anyhow!("Tree db has no root CID, pass it with --root"))?,
+    };
+    info!(config, "Root CID: {}", root);
+
+    let mut car = car_options.writer(path, vec![root.0])?;
+
+    let count = if depth_first {
+        write_tree_depth_first(&cid_tree, &root.0, car.as_mut())?
+    } else {
+        let mut count = 0;
+        let progress = config.progress(Some(cid_tree.len() as u64));
+        for entry in cid_tree.iter() {
+            let (cid, block) = entry?;
+            car.write_block(&cid, &block)?;
+            count += 1;
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+        count
+    };
+    let duplicates = car.duplicates();
+    car.finish()?;
+    if car_options.dedup.is_some() {
+        info!(config, "Duplicates: {}", duplicates);
+    }
+
+    println!("Blocks: {}", count);
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use crate::config::{info, CarOptions, Config};
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use hamt_rs::{
+    input::{Column, Input, InputFormat},
+    Encoding, NumberPolicy, NumberStats, Value,
+};
+use indicatif::ParallelProgressIterator;
+use multihash::{Code, MultihashDigest};
+use rayon::prelude::*;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct IngestArgs {
+    /// Dataset to ingest, in the layout given by --format
+    file: PathBuf,
+    /// Also write every record block to this car
+    #[structopt(long)]
+    car: Option<PathBuf>,
+    #[structopt(flatten)]
+    options: IngestOptions,
+    #[structopt(flatten)]
+    car_options: CarOptions,
+}
+
+// How records are read and encoded
+#[derive(StructOpt)]
+pub struct IngestOptions {
+    /// Layout of the dataset: tsv, csv, jsonl (one object per line) or json (an array of
+    /// objects)
+    #[structopt(long, default_value = "tsv")]
+    format: InputFormat,
+    /// Column holding the key, by position from 1 or by name with --header, as name:<name>
+    /// if the name is a number. For JSON, the field holding the key. Defaults to the 2nd
+    /// column of a tsv
+    #[structopt(long)]
+    key_column: Option<Column>,
+    /// Column holding the record as JSON text, by position from 1 or by name with --header,
+    /// as name:<name> if the name is a number. For JSON, the field holding the record. Defaults to the 5th column of a tsv, every
+    /// column of a csv with --header, or the whole object
+    #[structopt(long)]
+    record_column: Option<Column>,
+    /// The first row of a tsv or csv holds the column names
+    #[structopt(long)]
+    header: bool,
+    /// Number of records in the dataset, only used to show progress
+    #[structopt(long)]
+    records: Option<u64>,
+    /// How records are encoded: strict DAG-CBOR, or legacy to reproduce CIDs from older runs
+    #[structopt(long, default_value = "strict")]
+    encoding: Encoding,
+    /// What to do with numbers that do not fit in a 64 bit integer or an f64 without losing
+    /// precision: float, bignum, string or reject the record
+    #[structopt(long, default_value = "float")]
+    numbers: NumberPolicy,
+}
+
+impl IngestOptions {
+    fn input(&self) -> Input {
+        let mut input = Input::new(self.format).with_header(self.header);
+        if let Some(key) = &self.key_column {
+            input = input.with_key(key.clone());
+        }
+        if let Some(record) = &self.record_column {
+            input = input.with_record(record.clone());
+        }
+        input
+    }
+}
+
+type ChannelVal = (Vec<u8>, Vec<u8>);
+
+pub fn run(config: &Config, args: IngestArgs) -> Result<()> {
+    let car = args.car.as_deref().map(|path| (path, &args.car_options));
+    ingest(config, &args.file, car, &args.options)
+}
+
+/// Ingest `file` into the block db, writing the record blocks to `car` if given
+pub fn ingest(
+    config: &Config,
+    file: &Path,
+    car: Option<(&Path, &CarOptions)>,
+    options: &IngestOptions,
+) -> Result<()> {
+    // Without arbitrary precision serde_json has already rounded such numbers to an f64
+    if !cfg!(feature = "arbitrary-precision") && !matches!(options.numbers, NumberPolicy::Float) {
+        return Err(anyhow!(
+            "--numbers {} needs hamt built with --features arbitrary-precision",
+            options.numbers
+        ));
+    }
+
+    let db = config.open_block_db()?;
+    let hash_keycid = db.open_tree("hash_keycid")?;
+
+    let (tx, rx) = mpsc::channel::<ChannelVal>();
+
+    // Setup thread for writing out the .car file
+    let dedup = car.is_some_and(|(_, car_options)| car_options.dedup.is_some());
+    let writer = match car {
+        Some((path, car_options)) => {
+            let mut car = car_options.writer(path, vec![])?;
+
+            Some(thread::spawn(move || -> Result<u64> {
+                // Rejected records are never sent, so write until every sender is gone
+                for (cid, block) in rx {
+                    car.write_block(&cid, &block)?;
+                }
+                let duplicates = car.duplicates();
+                car.finish()?;
+                Ok(duplicates)
+            }))
+        }
+        None => None,
+    };
+
+    // Reserve one thread for the writer
+    let threads = match writer {
+        Some(_) => config.threads().saturating_sub(1).max(1),
+        None => config.threads(),
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    let numbers = NumberStats::default();
+    let rejected = AtomicU64::new(0);
+    let ingested = AtomicU64::new(0);
+
+    let file = BufReader::with_capacity(128 * 1024, File::open(file)?);
+    let (rows, parser) = options.input().open(file)?;
+    let progress = config.progress(options.records);
+    let write_to_car = writer.is_some();
+
+    // Process records in parallel
+    pool.install(|| {
+        rows.par_bridge()
+            .progress_with(progress.clone())
+            .map(|row| -> Result<Option<ChannelVal>> {
+                let (key, record) = parser.parse(row?)?;
+                let record = Value(record);
+                let block =
+                    match record.to_dag_cbor_with(options.encoding, options.numbers, &numbers) {
+                        Ok(block) => block,
+                        Err(e) => {
+                            progress.println(format!("Rejected record {}: {}", key, e));
+                            rejected.fetch_add(1, Ordering::Relaxed);
+                            return Ok(None);
+                        }
+                    };
+
+                let keybytes = key.as_bytes();
+                let keyhash = Code::Sha2_256.digest(keybytes);
+
+                // 0x71 - dag_cbor - https://github.com/multiformats/multicodec/blob/master/table.csv#L44
+                let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(&block));
+                let cidbytes = cid.to_bytes();
+
+                let keycid = bincode::serialize(&(keybytes, &cidbytes))?;
+                hash_keycid.insert(keyhash.digest(), keycid)?;
+                ingested.fetch_add(1, Ordering::Relaxed);
+
+                Ok(Some((cidbytes, block)))
+            })
+            .try_for_each_with(tx, |tx, value| -> Result<()> {
+                match value? {
+                    Some(value) if write_to_car => tx.send(value)?,
+                    _ => {}
+                }
+                Ok(())
+            })
+    })?;
+    progress.finish_and_clear();
+    db.flush()?;
+
+    if let Some(writer) = writer {
+        let duplicates = writer
+            .join()
+            .map_err(|_| anyhow!("Car writer panicked"))??;
+        if dedup {
+            info!(config, "Duplicates: {}", duplicates);
+        }
+    }
+
+    info!(
+        config,
+        "Encoding: {} Numbers: {}", options.encoding, options.numbers
+    );
+    info!(
+        config,
+        "Rounded: {} Bignums: {} Strings: {} Rejected: {}",
+        numbers.rounded(),
+        numbers.bignums(),
+        numbers.strings(),
+        rejected.load(Ordering::Relaxed)
+    );
+    println!("Records: {}", ingested.load(Ordering::Relaxed));
+    Ok(())
+}
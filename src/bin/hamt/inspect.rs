@@ -0,0 +1,74 @@
+use crate::config::Config;
+use anyhow::Result;
+use hamt_rs::{car::CarReader, load_root};
+use indicatif::ProgressBar;
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct InspectArgs {
+    path: PathBuf,
+    /// List the CID and size of every block in a car
+    #[structopt(long)]
+    blocks: bool,
+}
+
+pub fn run(config: &Config, args: InspectArgs) -> Result<()> {
+    match args.path.is_dir() {
+        true => inspect_db(&args),
+        false => inspect_car(config, &args),
+    }
+}
+
+fn inspect_car(config: &Config, args: &InspectArgs) -> Result<()> {
+    let mut reader = CarReader::new(BufReader::new(File::open(&args.path)?))?;
+
+    println!("Version: {}", reader.version());
+    for root in reader.roots() {
+        println!("Root: {}", root);
+    }
+
+    let mut count = 0;
+    let mut bytes = 0;
+    let mut codecs: BTreeMap<u64, u64> = BTreeMap::new();
+    // The listing goes to stdout and already shows progress, so the spinner would only
+    // draw over it
+    let progress = match args.blocks {
+        true => ProgressBar::hidden(),
+        false => config.progress(None),
+    };
+    for block in reader.blocks()? {
+        let (cid, block) = block?;
+        if args.blocks {
+            println!("{} {}", cid, block.len());
+        }
+        count += 1;
+        bytes += block.len() as u64;
+        *codecs.entry(cid.codec()).or_default() += 1;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    println!("Blocks: {} Bytes: {}", count, bytes);
+    for (codec, count) in codecs {
+        println!("Codec {:#x}: {}", codec, count);
+    }
+    Ok(())
+}
+
+fn inspect_db(args: &InspectArgs) -> Result<()> {
+    let db = sled::open(&args.path)?;
+
+    let names = db.tree_names();
+    // Only tree dbs have a root, and looking for it would create the tree holding it
+    if names.iter().any(|name| name.as_ref() == b"meta") {
+        if let Some(root) = load_root(&db)? {
+            println!("Root CID: {}", root);
+        }
+    }
+    for name in names {
+        let tree = db.open_tree(&name)?;
+        println!("{}: {}", String::from_utf8_lossy(&name), tree.len());
+    }
+    Ok(())
+}
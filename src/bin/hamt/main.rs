@@ -0,0 +1,87 @@
+mod build;
+mod config;
+mod export;
+mod ingest;
+mod inspect;
+mod pipeline;
+mod query;
+mod verify;
+
+use anyhow::Result;
+use config::Config;
+use structopt::StructOpt;
+
+/// Build, export and query IPLD HAMTs
+#[derive(StructOpt)]
+#[structopt(name = "hamt")]
+struct Cli {
+    #[structopt(flatten)]
+    config: Config,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Encode every record of a dataset as a block and index its CID by the hash of its key
+    Ingest(ingest::IngestArgs),
+    /// Build the HAMT from the block db into the tree db
+    Build(build::BuildArgs),
+    /// Write the tree to a car
+    Export(export::ExportArgs),
+    /// Look up a key through the local IPFS daemon and print its record
+    Query(query::QueryArgs),
+    /// Summarize a car, or a block or tree db
+    Inspect(inspect::InspectArgs),
+    /// Check every block in a car against its CID, and that the car holds its roots
+    Verify(verify::VerifyArgs),
+    /// Ingest a dataset, build the tree and write the block and tree cars in one go
+    Pipeline(pipeline::PipelineArgs),
+}
+
+fn main() -> Result<()> {
+    let args = Cli::from_args();
+    let config = args.config;
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads())
+        .build_global()?;
+
+    match args.command {
+        Command::Ingest(args) => ingest::run(&config, args),
+        Command::Build(args) => build::run(&config, args),
+        Command::Export(args) => export::run(&config, args),
+        Command::Query(args) => tokio::runtime::Runtime::new()?.block_on(query::run(&config, args)),
+        Command::Inspect(args) => inspect::run(&config, args),
+        Command::Verify(args) => verify::run(&config, args),
+        Command::Pipeline(args) => pipeline::run(&config, args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn global_options_go_after_the_subcommand() {
+        let args =
+            Cli::from_iter_safe(["hamt", "build", "--tree-db", "t.db", "-q", "--threads", "2"])
+                .unwrap();
+        assert_eq!(args.config.tree_db, PathBuf::from("t.db"));
+        assert_eq!(args.config.block_db, PathBuf::from("blocks.db"));
+        assert!(args.config.quiet);
+        assert_eq!(args.config.threads(), 2);
+        assert!(matches!(args.command, Command::Build(_)));
+    }
+
+    #[test]
+    fn split_cars_cannot_be_carv2() {
+        let split = ["hamt", "export", "tree.car", "--split-bytes", "1024"];
+        assert!(Cli::from_iter_safe(split).is_ok());
+        assert!(Cli::from_iter_safe([&split[..], &["--v2"]].concat()).is_err());
+        assert!(
+            Cli::from_iter_safe(["hamt", "export", "tree.car", "--dedup", "sometimes"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    build::build,
+    config::{info, CarOptions, Config, TreeOptions},
+    export::export_tree,
+    ingest::{ingest, IngestOptions},
+};
+use anyhow::Result;
+use std::{fs, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct PipelineArgs {
+    /// Dataset to ingest, in the layout given by --format
+    file: PathBuf,
+    /// Directory the block car and tree car are written to
+    #[structopt(long, default_value = ".")]
+    out: PathBuf,
+    /// Write the tree car in depth-first order
+    #[structopt(long)]
+    depth_first: bool,
+    #[structopt(flatten)]
+    ingest: IngestOptions,
+    #[structopt(flatten)]
+    tree: TreeOptions,
+    #[structopt(flatten)]
+    car_options: CarOptions,
+}
+
+pub fn run(config: &Config, args: PipelineArgs) -> Result<()> {
+    fs::create_dir_all(&args.out)?;
+    let block_car = args.out.join("blocks.car");
+    let tree_car = args.out.join("tree.car");
+
+    info!(config, "Ingesting {}", args.file.display());
+    ingest(
+        config,
+        &args.file,
+        Some((&block_car, &args.car_options)),
+        &args.ingest,
+    )?;
+
+    info!(config, "Building tree");
+    let root = build(config, &args.tree)?;
+
+    info!(config, "Exporting tree");
+    export_tree(
+        config,
+        &tree_car,
+        Some(root),
+        args.depth_first,
+        &args.car_options,
+    )?;
+
+    info!(
+        config,
+        "Import with `ipfs dag import {} {}`",
+        block_car.display(),
+        tree_car.display()
+    );
+    Ok(())
+}
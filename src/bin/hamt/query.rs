@@ -0,0 +1,95 @@
+use crate::config::{info, Config};
+use anyhow::{anyhow, Result};
+use hamt_rs::{
+    load_root,
+    multibase::Base,
+    parse_base,
+    query::{Record, RootMapBlock},
+    source::{BlockSource, IpfsSource},
+    Cid, CidDecoder, CidMode, Value,
+};
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct QueryArgs {
+    key: String,
+    /// Root CID of the HAMT, read from the tree db if not given
+    #[structopt(long)]
+    root: Option<Cid>,
+    /// How to print the record: json, cbor (diagnostic notation) or cid
+    #[structopt(long, default_value = "json")]
+    format: Format,
+    /// Multibase used to print the CID with --format cid, such as base32 or base58btc
+    #[structopt(long, default_value = "base32", parse(try_from_str = parse_base))]
+    base: Base,
+    /// Check links in the record: strict only accepts CIDv1 dag-cbor and raw links, lenient
+    /// also accepts dag-pb and CIDv0 links
+    #[structopt(long)]
+    cids: Option<CidMode>,
+}
+
+enum Format {
+    Json,
+    Cbor,
+    Cid,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "cbor" => Ok(Format::Cbor),
+            "cid" => Ok(Format::Cid),
+            _ => Err(format!("Unknown format {}, expected json, cbor or cid", s)),
+        }
+    }
+}
+
+pub async fn run(config: &Config, args: QueryArgs) -> Result<()> {
+    let root = match args.root {
+        Some(root) => root,
+        None => load_root(&config.open_tree_db()?)?
+            .ok_or_else(|| anyhow!("Tree db has no root CID, pass it with --root"))?,
+    };
+    info!(config, "Root CID: {}", root);
+
+    let block = IpfsSource::default().get(&root).await?;
+    let mut root: RootMapBlock = minicbor::decode(&block)?;
+    let cids = args.cids.map(CidDecoder::new);
+    if let Some(cids) = &cids {
+        root = root.with_cids(cids.clone());
+    }
+
+    let key = args.key.as_bytes();
+
+    match args.format {
+        Format::Cid => {
+            let cid = root.get_key(key).await?.ok_or_else(not_found)?;
+            if let Some(cids) = &cids {
+                cids.check(&cid)?;
+            }
+            println!("{}", cid.to_string_of_base(args.base)?);
+        }
+        Format::Json => {
+            let record = get_record(&root, key).await?;
+            let json = match &cids {
+                Some(cids) => Value::from_dag_cbor_with(&record.block, cids)?.0,
+                None => record.json()?,
+            };
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        Format::Cbor => println!("{}", get_record(&root, key).await?.diagnostic()),
+    }
+    Ok(())
+}
+
+async fn get_record(root: &RootMapBlock, key: &[u8]) -> Result<Record> {
+    root.get_record(key).await?.ok_or_else(not_found)
+}
+
+fn not_found() -> anyhow::Error {
+    anyhow!("Key not found")
+}
@@ -0,0 +1,36 @@
+use crate::config::Config;
+use anyhow::{bail, Result};
+use hamt_rs::car::CarReader;
+use std::{collections::HashSet, fs::File, io::BufReader, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct VerifyArgs {
+    car: PathBuf,
+    /// The car is one volume of a split car, so its roots may be in another volume
+    #[structopt(long)]
+    partial: bool,
+}
+
+pub fn run(config: &Config, args: VerifyArgs) -> Result<()> {
+    let mut reader = CarReader::new(BufReader::new(File::open(&args.car)?))?.verify(true);
+    let mut roots: HashSet<_> = reader.roots().iter().cloned().collect();
+
+    let mut count = 0;
+    let progress = config.progress(None);
+    for block in reader.blocks()? {
+        let (cid, _) = block?;
+        roots.remove(&cid);
+        count += 1;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    if !args.partial && !roots.is_empty() {
+        let missing: Vec<String> = roots.iter().map(|root| root.to_string()).collect();
+        bail!("Car is missing its roots {}", missing.join(", "));
+    }
+
+    println!("Verified {} blocks", count);
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use crate::query::MapBlock;
+use crate::Cid;
+use lru::LruCache;
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// Upper bound on the contents of a `NodeCache`.
+#[derive(Debug, Clone, Copy)]
+pub enum CacheLimit {
+    /// Maximum number of decoded nodes held at once
+    Entries(usize),
+    /// Maximum total size of the raw blocks the held nodes were decoded from
+    Bytes(usize),
+}
+
+/// A size bounded LRU cache of decoded HAMT nodes keyed by CID.
+///
+/// A single cache can be shared between any number of `RootMapBlock`s
+/// through an `Arc`, so the upper levels of a tree are only fetched once.
+#[derive(Debug)]
+pub struct NodeCache {
+    nodes: Mutex<Nodes>,
+    limit: CacheLimit,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Nodes {
+    lru: LruCache<Cid, (Arc<MapBlock>, usize)>,
+    bytes: usize,
+}
+
+impl NodeCache {
+    pub fn new(limit: CacheLimit) -> Self {
+        NodeCache {
+            nodes: Mutex::new(Nodes {
+                lru: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            limit,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, cid: &Cid) -> Option<Arc<MapBlock>> {
+        let mut nodes = self.nodes.lock().unwrap();
+
+        match nodes.lru.get(cid) {
+            Some((node, _)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(node.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Get `cid` without touching its recency or the hit and miss counters
+    pub(crate) fn peek(&self, cid: &Cid) -> Option<Arc<MapBlock>> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .lru
+            .peek(cid)
+            .map(|(node, _)| Arc::clone(node))
+    }
+
+    /// Check for `cid` without touching its recency or the hit and miss counters
+    pub(crate) fn contains(&self, cid: &Cid) -> bool {
+        self.nodes.lock().unwrap().lru.contains(cid)
+    }
+
+    pub(crate) fn insert(&self, cid: Cid, node: Arc<MapBlock>, size: usize) {
+        let mut nodes = self.nodes.lock().unwrap();
+
+        if let Some((_, old_size)) = nodes.lru.put(cid, (node, size)) {
+            nodes.bytes -= old_size;
+        }
+        nodes.bytes += size;
+
+        while !nodes.lru.is_empty() && self.over_limit(&nodes) {
+            if let Some((_, (_, evicted_size))) = nodes.lru.pop_lru() {
+                nodes.bytes -= evicted_size;
+            }
+        }
+    }
+
+    fn over_limit(&self, nodes: &Nodes) -> bool {
+        match self.limit {
+            CacheLimit::Entries(max) => nodes.lru.len() > max,
+            CacheLimit::Bytes(max) => nodes.bytes > max,
+        }
+    }
+
+    pub fn limit(&self) -> CacheLimit {
+        self.limit
+    }
+
+    /// Number of lookups that were served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of lookups that had to fetch the node from the network
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.lock().unwrap().lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the raw blocks behind the cached nodes
+    pub fn bytes(&self) -> usize {
+        self.nodes.lock().unwrap().bytes
+    }
+
+    pub fn clear(&self) {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.lru.clear();
+        nodes.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::Cid as ExtCid;
+    use multihash::{Code, MultihashDigest};
+
+    fn cid(n: u8) -> Cid {
+        Cid(ExtCid::new_v1(0x71, Code::Sha2_256.digest(&[n])))
+    }
+
+    fn node() -> Arc<MapBlock> {
+        // A node with an empty bitmap and no data
+        Arc::new(minicbor::decode(&[0x82, 0x40, 0x80]).unwrap())
+    }
+
+    fn cached(cache: &NodeCache, n: u8) -> bool {
+        cache.contains(&cid(n))
+    }
+
+    #[test]
+    fn entries_limit_evicts_least_recently_used() {
+        let cache = NodeCache::new(CacheLimit::Entries(2));
+        cache.insert(cid(1), node(), 10);
+        cache.insert(cid(2), node(), 10);
+        assert!(cache.get(&cid(1)).is_some());
+        cache.insert(cid(3), node(), 10);
+
+        assert!(cached(&cache, 1));
+        assert!(!cached(&cache, 2));
+        assert!(cached(&cache, 3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), 20);
+    }
+
+    #[test]
+    fn bytes_limit_evicts_until_under_limit() {
+        let cache = NodeCache::new(CacheLimit::Bytes(100));
+        cache.insert(cid(1), node(), 40);
+        cache.insert(cid(2), node(), 40);
+        cache.insert(cid(3), node(), 20);
+        assert!(cache.get(&cid(1)).is_some());
+        cache.insert(cid(4), node(), 50);
+
+        assert!(cached(&cache, 1));
+        assert!(!cached(&cache, 2));
+        assert!(!cached(&cache, 3));
+        assert!(cached(&cache, 4));
+        assert_eq!(cache.bytes(), 90);
+
+        // Replacing an entry only counts its new size
+        cache.insert(cid(4), node(), 10);
+        assert_eq!(cache.bytes(), 50);
+
+        // A node larger than the limit is not kept
+        cache.insert(cid(5), node(), 200);
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = NodeCache::new(CacheLimit::Entries(4));
+        assert!(cache.get(&cid(1)).is_none());
+        cache.insert(cid(1), node(), 10);
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(2)).is_none());
+
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn peek_and_contains_do_not_touch_recency_or_counters() {
+        let cache = NodeCache::new(CacheLimit::Entries(2));
+        cache.insert(cid(1), node(), 10);
+        cache.insert(cid(2), node(), 10);
+        assert!(cache.peek(&cid(1)).is_some());
+        assert!(cache.contains(&cid(1)));
+        assert!(cache.peek(&cid(3)).is_none());
+        cache.insert(cid(3), node(), 10);
+
+        // 1 is still the least recently used
+        assert!(!cached(&cache, 1));
+        assert!(cached(&cache, 2));
+        assert_eq!(cache.hits(), 0);
+        assert_eq!(cache.misses(), 0);
+    }
+}
@@ -1,26 +1,143 @@
-use anyhow::Result;
+use crate::{query, source::BlockSource};
+use anyhow::{anyhow, Result};
 use cid::Cid;
+use futures::{
+    future::BoxFuture,
+    stream::{self, Stream},
+};
+use minicbor::{encode, Encode};
+use multihash::{Code, MultihashDigest};
+use serde::Serialize;
 
-use std::io::{self, Write};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use unsigned_varint::encode::{usize, usize_buffer};
 
-const EMPTY_CAR_HEADER: &[u8] = include_bytes!("empty.car");
+/// An empty dag-cbor map, used as the root of CARs that have no real root
+const EMPTY_MAP_BLOCK: &[u8] = &[0xa0];
 
-pub struct Car {
-    header: &'static [u8],
-    file: Box<dyn Write>,
+/// Largest header a reader accepts, so a corrupt length cannot allocate without bound
+const MAX_HEADER_SIZE: u64 = 32 << 20;
+
+/// Largest section, a CID and its block, a reader accepts
+const MAX_SECTION_SIZE: u64 = 32 << 20;
+
+fn placeholder_root() -> Cid {
+    Cid::new_v1(0x71, Code::Sha2_256.digest(EMPTY_MAP_BLOCK))
+}
+
+pub struct Car<W: Write = Box<dyn Write>> {
+    header: Vec<u8>,
+    placeholder_root: bool,
+    file: W,
+    position: u64,
+    dedup: Option<Dedup>,
+}
+
+/// The CIDs a deduplicating writer has already written
+pub enum SeenSet {
+    /// Exact, but holds every CID in memory
+    Memory(HashSet<Vec<u8>>),
+    /// Keeps the CIDs in a sled tree, for runs too large to hold in memory
+    Disk(sled::Tree),
+}
+
+impl SeenSet {
+    pub fn memory() -> Self {
+        SeenSet::Memory(HashSet::new())
+    }
+
+    /// Use `tree` as the seen-set. It should start empty, as any CIDs in it are skipped.
+    pub fn disk(tree: sled::Tree) -> Self {
+        SeenSet::Disk(tree)
+    }
+
+    /// Record `cid`, returning whether it was new
+    fn insert(&mut self, cid: &[u8]) -> Result<bool> {
+        match self {
+            SeenSet::Memory(seen) => Ok(seen.insert(cid.to_vec())),
+            SeenSet::Disk(seen) => Ok(seen.insert(cid, &[])?.is_none()),
+        }
+    }
 }
 
-impl Car {
-    pub fn new(file: Box<dyn Write>) -> Self {
+struct Dedup {
+    seen: SeenSet,
+    duplicates: u64,
+}
+
+impl Dedup {
+    fn new(seen: SeenSet) -> Self {
+        Dedup {
+            seen,
+            duplicates: 0,
+        }
+    }
+}
+
+/// Whether `cid` has already been written, counting it as a duplicate if so
+fn is_duplicate(dedup: &mut Option<Dedup>, cid: &[u8]) -> Result<bool> {
+    if let Some(dedup) = dedup {
+        if !dedup.seen.insert(cid)? {
+            dedup.duplicates += 1;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl<W: Write> Car<W> {
+    /// Start a CARv1 with `roots` in its header.
+    ///
+    /// Some importers reject CARs without roots, so when `roots` is empty the CID of
+    /// an empty map is used as the root and that block is written after the header.
+    pub fn new(file: W, roots: Vec<Cid>) -> Self {
+        let (header, placeholder_root) = encode_roots(roots);
+
         Car {
-            header: EMPTY_CAR_HEADER,
+            header,
+            placeholder_root,
             file,
+            position: 0,
+            dedup: None,
+        }
+    }
+
+    /// Skip blocks whose CID is already in `seen`.
+    pub fn with_dedup(mut self, seen: SeenSet) -> Self {
+        self.dedup = Some(Dedup::new(seen));
+        self
+    }
+
+    /// Number of blocks skipped because their CID was already written
+    pub fn duplicates(&self) -> u64 {
+        self.dedup.as_ref().map_or(0, |dedup| dedup.duplicates)
+    }
+
+    pub fn encode_header(&mut self) -> Result<()> {
+        self.write_header()?;
+
+        if self.placeholder_root {
+            self.write_block_cid(&placeholder_root(), EMPTY_MAP_BLOCK)?;
         }
+        Ok(())
     }
 
-    pub fn encode_header(&mut self) -> io::Result<()> {
-        self.file.write_all(self.header)
+    fn write_header(&mut self) -> Result<()> {
+        let mut buffer = usize_buffer();
+        let length = usize(self.header.len(), &mut buffer);
+        self.file.write_all(length)?;
+        self.file.write_all(&self.header)?;
+
+        self.position += (length.len() + self.header.len()) as u64;
+        Ok(())
     }
 
     pub fn write_block_cid(&mut self, cid: &Cid, block: &[u8]) -> Result<()> {
@@ -28,10 +145,1483 @@ impl Car {
     }
 
     pub fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
-        self.file
-            .write_all(usize(cid.len() + block.len(), &mut usize_buffer()))?;
+        if is_duplicate(&mut self.dedup, cid)? {
+            return Ok(());
+        }
+        self.write_section(cid, block)
+    }
+
+    fn write_section(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        let mut buffer = usize_buffer();
+        let length = usize(cid.len() + block.len(), &mut buffer);
+        self.file.write_all(length)?;
         self.file.write_all(cid)?;
         self.file.write_all(block)?;
+
+        self.position += (length.len() + cid.len() + block.len()) as u64;
+        Ok(())
+    }
+
+    /// Number of bytes written so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> W {
+        self.file
+    }
+}
+
+/// The header for `roots`, and whether it uses the placeholder root because `roots` is empty
+fn encode_roots(roots: Vec<Cid>) -> (Vec<u8>, bool) {
+    let placeholder = roots.is_empty();
+    let roots = match placeholder {
+        true => vec![placeholder_root()],
+        false => roots,
+    };
+
+    (
+        minicbor::to_vec(CarHeader { roots: &roots }).unwrap(),
+        placeholder,
+    )
+}
+
+/// The operations shared by the CAR writers, so tools can pick a format at runtime
+pub trait BlockWriter {
+    fn encode_header(&mut self) -> Result<()>;
+    fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()>;
+    /// Number of blocks skipped as duplicates, always zero unless dedup is enabled
+    fn duplicates(&self) -> u64;
+    /// Flush everything still buffered and complete the file
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl<W: Write> BlockWriter for Car<W> {
+    fn encode_header(&mut self) -> Result<()> {
+        Car::encode_header(self)
+    }
+
+    fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        Car::write_block(self, cid, block)
+    }
+
+    fn duplicates(&self) -> u64 {
+        Car::duplicates(self)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        Ok(self.file.flush()?)
+    }
+}
+
+/// The fixed bytes that start every CARv2 file, a CARv1 style header of `{"version": 2}`
+const CARV2_PRAGMA: &[u8] = &[
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+
+/// Length of the CARv2 header: characteristics, data offset, data size and index offset
+const CARV2_HEADER_LEN: usize = 40;
+
+/// Multicodec of the `MultihashIndexSorted` index format
+const MULTIHASH_INDEX_SORTED: usize = 0x0401;
+
+/// A CARv2 writer, wrapping a CARv1 payload with an index of every block it holds.
+///
+/// The pragma and header are only known once all blocks are written, so space is reserved
+/// for them up front and `finish` seeks back to fill them in after writing the index.
+pub struct CarV2<W: Write + Seek> {
+    car: Car<W>,
+    /// Index entries of `digest || offset`, grouped by multihash code and digest length
+    index: BTreeMap<(u64, usize), Vec<u8>>,
+}
+
+impl<W: Write + Seek> CarV2<W> {
+    pub fn new(mut file: W, roots: Vec<Cid>) -> Result<Self> {
+        file.write_all(&[0; CARV2_PRAGMA.len() + CARV2_HEADER_LEN])?;
+
+        Ok(CarV2 {
+            car: Car::new(file, roots),
+            index: BTreeMap::new(),
+        })
+    }
+
+    /// Skip blocks whose CID is already in `seen`, leaving them out of the index too.
+    pub fn with_dedup(mut self, seen: SeenSet) -> Self {
+        self.car = self.car.with_dedup(seen);
+        self
+    }
+
+    pub fn encode_header(&mut self) -> Result<()> {
+        self.car.write_header()?;
+
+        if self.car.placeholder_root {
+            self.write_block_cid(&placeholder_root(), EMPTY_MAP_BLOCK)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_block_cid(&mut self, cid: &Cid, block: &[u8]) -> Result<()> {
+        self.write_block(&cid.to_bytes(), block)
+    }
+
+    pub fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        if is_duplicate(&mut self.car.dedup, cid)? {
+            return Ok(());
+        }
+
+        let hash = *Cid::try_from(cid)?.hash();
+        let entries = self
+            .index
+            .entry((hash.code(), hash.digest().len()))
+            .or_default();
+        entries.extend_from_slice(hash.digest());
+        entries.extend_from_slice(&self.car.position().to_le_bytes());
+
+        self.car.write_section(cid, block)
+    }
+
+    /// Write the index after the blocks, then the pragma and header at the start of the file.
+    pub fn finish(self) -> Result<W> {
+        let data_offset = (CARV2_PRAGMA.len() + CARV2_HEADER_LEN) as u64;
+        let data_size = self.car.position();
+        let index_offset = data_offset
+            .checked_add(data_size)
+            .ok_or_else(|| anyhow!("CARv2 payload of {} bytes is too large", data_size))?;
+        let mut file = self.car.into_inner();
+
+        file.write_all(usize(MULTIHASH_INDEX_SORTED, &mut usize_buffer()))?;
+        write_multihash_index_sorted(&mut file, self.index)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(CARV2_PRAGMA)?;
+        // No characteristics are set
+        file.write_all(&[0; 16])?;
+        file.write_all(&data_offset.to_le_bytes())?;
+        file.write_all(&data_size.to_le_bytes())?;
+        file.write_all(&index_offset.to_le_bytes())?;
+
+        file.seek(SeekFrom::End(0))?;
+        file.flush()?;
+        Ok(file)
+    }
+}
+
+impl<W: Write + Seek> BlockWriter for CarV2<W> {
+    fn encode_header(&mut self) -> Result<()> {
+        CarV2::encode_header(self)
+    }
+
+    fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        CarV2::write_block(self, cid, block)
+    }
+
+    fn duplicates(&self) -> u64 {
+        self.car.duplicates()
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        CarV2::finish(*self).map(|_| ())
+    }
+}
+
+/// Limits on the size of each volume written by `SplitCar`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SplitLimit {
+    pub bytes: Option<u64>,
+    pub blocks: Option<u64>,
+}
+
+/// Writes blocks across numbered CARv1 volumes, starting a new one whenever the next block
+/// would take the current volume past its limit.
+///
+/// Every volume is a complete CARv1, so each can be imported on its own. Only a volume
+/// holding a root block lists that root in its header, the others get the placeholder
+/// root. Which roots a volume holds is only known once it is full, so its blocks are
+/// written to a `.part` file first and copied after the header when the volume is closed.
+/// The placeholder block is not counted against the block limit. A block larger than the
+/// byte limit still gets a volume to itself. `finish` writes a manifest listing the volumes and which of them hold the roots.
+pub struct SplitCar {
+    /// Volumes are written to `<directory>/<stem>.<number>.car`
+    directory: PathBuf,
+    stem: String,
+    roots: Vec<Cid>,
+    root_bytes: Vec<Vec<u8>>,
+    limit: SplitLimit,
+    /// The largest header a volume can get, counted against the byte limit
+    header_bytes: u64,
+    /// The blocks of the current volume, without a header
+    car: Option<Car<BufWriter<File>>>,
+    /// The roots whose blocks are in the current volume
+    volume_roots: Vec<Cid>,
+    volumes: Vec<Volume>,
+    /// Shared by every volume, so a block is only written to the first one it appears in
+    dedup: Option<Dedup>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub roots: Vec<String>,
+    pub volumes: Vec<Volume>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Volume {
+    pub file: String,
+    pub blocks: u64,
+    pub bytes: u64,
+    /// The roots whose blocks are in this volume
+    pub roots: Vec<String>,
+}
+
+impl SplitCar {
+    /// Volumes are named after `path`, so `out/tree.car` gives `out/tree.00000.car` and
+    /// so on, with the manifest at `out/tree.manifest.json`.
+    pub fn new(path: &Path, roots: Vec<Cid>, limit: SplitLimit) -> Self {
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "car".to_string());
+
+        let header_bytes = |roots: Vec<Cid>| {
+            let mut car = Car::new(vec![], roots);
+            car.encode_header().expect("writing to a Vec does not fail");
+            car.position()
+        };
+
+        SplitCar {
+            directory: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            stem,
+            root_bytes: roots.iter().map(Cid::to_bytes).collect(),
+            header_bytes: header_bytes(roots.clone()).max(header_bytes(vec![])),
+            roots,
+            limit,
+            car: None,
+            volume_roots: vec![],
+            volumes: vec![],
+            dedup: None,
+        }
+    }
+
+    /// Skip blocks whose CID is already in `seen` or in an earlier volume.
+    pub fn with_dedup(mut self, seen: SeenSet) -> Self {
+        self.dedup = Some(Dedup::new(seen));
+        self
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.dedup.as_ref().map_or(0, |dedup| dedup.duplicates)
+    }
+
+    pub fn encode_header(&mut self) -> Result<()> {
+        self.next_volume()
+    }
+
+    pub fn write_block_cid(&mut self, cid: &Cid, block: &[u8]) -> Result<()> {
+        self.write_block(&cid.to_bytes(), block)
+    }
+
+    pub fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        if is_duplicate(&mut self.dedup, cid)? {
+            return Ok(());
+        }
+
+        let length = cid.len() + block.len();
+        let section = (usize(length, &mut usize_buffer()).len() + length) as u64;
+
+        let volume = self
+            .volumes
+            .last()
+            .ok_or_else(|| anyhow!("Header was not written"))?;
+        let bytes = self.header_bytes + volume.bytes;
+        let over_bytes = matches!(self.limit.bytes, Some(max) if bytes + section > max);
+        let over_blocks = matches!(self.limit.blocks, Some(max) if volume.blocks + 1 > max);
+        if volume.blocks > 0 && (over_bytes || over_blocks) {
+            self.next_volume()?;
+        }
+
+        let car = self.car.as_mut().unwrap();
+        car.write_section(cid, block)?;
+
+        let volume = self.volumes.last_mut().unwrap();
+        volume.blocks += 1;
+        volume.bytes = car.position();
+        if let Some(root) = self.root_bytes.iter().position(|root| root == cid) {
+            volume.roots.push(self.roots[root].to_string());
+            self.volume_roots.push(self.roots[root]);
+        }
+        Ok(())
+    }
+
+    /// Close the last volume and write the manifest.
+    pub fn finish(mut self) -> Result<Manifest> {
+        self.close_volume()?;
+
+        let manifest = Manifest {
+            roots: self.roots.iter().map(Cid::to_string).collect(),
+            volumes: self.volumes,
+        };
+        let file = File::create(self.directory.join(format!("{}.manifest.json", self.stem)))?;
+        serde_json::to_writer_pretty(file, &manifest)?;
+        Ok(manifest)
+    }
+
+    fn next_volume(&mut self) -> Result<()> {
+        self.close_volume()?;
+
+        let name = format!("{}.{:05}.car", self.stem, self.volumes.len());
+        let part = self.directory.join(format!("{}.part", name));
+        let file = BufWriter::with_capacity(128 * 1024, File::create(part)?);
+
+        self.volumes.push(Volume {
+            file: name,
+            blocks: 0,
+            bytes: 0,
+            roots: vec![],
+        });
+        self.car = Some(Car::new(file, vec![]));
         Ok(())
     }
+
+    /// Write the header of the current volume for the roots it holds, followed by its blocks
+    fn close_volume(&mut self) -> Result<()> {
+        let car = match self.car.take() {
+            Some(car) => car,
+            None => return Ok(()),
+        };
+        car.into_inner().into_inner()?;
+
+        let volume = self.volumes.last_mut().unwrap();
+        let path = self.directory.join(&volume.file);
+        let part = self.directory.join(format!("{}.part", volume.file));
+
+        let file = BufWriter::with_capacity(128 * 1024, File::create(&path)?);
+        let mut car = Car::new(file, std::mem::take(&mut self.volume_roots));
+        car.encode_header()?;
+        volume.bytes += car.position();
+
+        let mut file = car.into_inner();
+        std::io::copy(&mut File::open(&part)?, &mut file)?;
+        file.flush()?;
+        std::fs::remove_file(part)?;
+        Ok(())
+    }
+}
+
+impl BlockWriter for SplitCar {
+    fn encode_header(&mut self) -> Result<()> {
+        SplitCar::encode_header(self)
+    }
+
+    fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        SplitCar::write_block(self, cid, block)
+    }
+
+    fn duplicates(&self) -> u64 {
+        SplitCar::duplicates(self)
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        SplitCar::finish(*self).map(|_| ())
+    }
+}
+
+/// Write the tree under `root` from a tree db in depth-first pre-order, returning the number
+/// of blocks written.
+///
+/// This is the order trustless gateways use, so a streaming reader can check every block
+/// against the parent it has already verified instead of buffering the whole CAR.
+pub fn write_tree_depth_first(
+    tree: &sled::Tree,
+    root: &Cid,
+    car: &mut dyn BlockWriter,
+) -> Result<u64> {
+    let mut stack = vec![(crate::Cid(*root), true)];
+    let mut count = 0;
+
+    while let Some((cid, is_root)) = stack.pop() {
+        let cid_bytes = cid.0.to_bytes();
+        let block = tree
+            .get(&cid_bytes)?
+            .ok_or_else(|| anyhow!("Block {} is missing from the tree db", cid))?;
+        car.write_block(&cid_bytes, &block)?;
+        count += 1;
+
+        // Reversed so the first child is popped next
+        let children = query::child_nodes(&block, is_root)?;
+        stack.extend(children.into_iter().rev().map(|child| (child, false)));
+    }
+
+    Ok(count)
+}
+
+/// Write entries in the layout of go-car's `MultihashIndexSorted`. Codes and digest widths
+/// are written in ascending order, each followed by its entries sorted by digest.
+fn write_multihash_index_sorted<W: Write>(
+    file: &mut W,
+    index: BTreeMap<(u64, usize), Vec<u8>>,
+) -> Result<()> {
+    let mut codes: BTreeMap<u64, Vec<(usize, Vec<u8>)>> = BTreeMap::new();
+    for ((code, digest_len), entries) in index {
+        codes.entry(code).or_default().push((digest_len, entries));
+    }
+
+    file.write_all(&(codes.len() as i32).to_le_bytes())?;
+    for (code, widths) in codes {
+        file.write_all(&code.to_le_bytes())?;
+        file.write_all(&(widths.len() as i32).to_le_bytes())?;
+
+        for (digest_len, entries) in widths {
+            // Each entry is the digest followed by a u64 offset
+            let width = digest_len + 8;
+            let mut sorted: Vec<&[u8]> = entries.chunks_exact(width).collect();
+            sorted.sort_unstable();
+
+            file.write_all(&(width as u32).to_le_bytes())?;
+            file.write_all(&(entries.len() as u64).to_le_bytes())?;
+            for entry in sorted {
+                file.write_all(entry)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct CarHeader<'a> {
+    roots: &'a [Cid],
+}
+
+impl Encode for CarHeader<'_> {
+    fn encode<W: encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), encode::Error<W::Error>> {
+        e.map(2)?;
+        e.str("roots")?;
+        e.array(self.roots.len() as u64)?;
+        for root in self.roots {
+            e.encode(crate::Cid(*root))?;
+        }
+        e.str("version")?;
+        e.u8(1)?;
+        Ok(())
+    }
+}
+
+/// Reads CARv1 and CARv2 files, either as a stream of blocks or by CID through an index.
+pub struct CarReader<R: Read + Seek> {
+    file: R,
+    roots: Vec<Cid>,
+    version: u64,
+    /// Start of the CARv1 payload, so zero for a CARv1
+    data_offset: u64,
+    /// End of the CARv1 payload
+    data_end: u64,
+    /// Start of the first block section
+    blocks_offset: u64,
+    index_offset: Option<u64>,
+    verify: bool,
+}
+
+impl<R: Read + Seek> CarReader<R> {
+    pub fn new(mut file: R) -> Result<Self> {
+        let file_end = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let (roots, version) = read_header(&mut file)?;
+        match version {
+            1 => {
+                let blocks_offset = file.stream_position()?;
+                Ok(CarReader {
+                    file,
+                    roots,
+                    version,
+                    data_offset: 0,
+                    data_end: file_end,
+                    blocks_offset,
+                    index_offset: None,
+                    verify: false,
+                })
+            }
+            2 => {
+                let mut header = [0; CARV2_HEADER_LEN];
+                file.read_exact(&mut header)?;
+                let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+                let (data_offset, data_size, index_offset) = (field(16), field(24), field(32));
+
+                let data_end = data_offset
+                    .checked_add(data_size)
+                    .filter(|end| *end <= file_end)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "CARv2 payload of {} bytes at offset {} runs past the end of the file",
+                            data_size,
+                            data_offset
+                        )
+                    })?;
+                if index_offset > file_end {
+                    return Err(anyhow!(
+                        "CARv2 index offset {} is past the end of the file",
+                        index_offset
+                    ));
+                }
+
+                file.seek(SeekFrom::Start(data_offset))?;
+                let (roots, inner_version) = read_header(&mut file)?;
+                if inner_version != 1 {
+                    return Err(anyhow!("CARv2 payload has version {}", inner_version));
+                }
+
+                let blocks_offset = file.stream_position()?;
+                Ok(CarReader {
+                    file,
+                    roots,
+                    version,
+                    data_offset,
+                    data_end,
+                    blocks_offset,
+                    index_offset: Some(index_offset).filter(|offset| *offset != 0),
+                    verify: false,
+                })
+            }
+            _ => Err(anyhow!("Unsupported CAR version {}", version)),
+        }
+    }
+
+    /// Check every block read against the hash in its CID.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Iterate over every block in the order they are stored.
+    pub fn blocks(&mut self) -> Result<Blocks<'_, R>> {
+        let position = self.file.seek(SeekFrom::Start(self.blocks_offset))?;
+        Ok(Blocks {
+            reader: self,
+            position,
+        })
+    }
+
+    /// Load the index of a CARv2, or build one by scanning the blocks if the file has none.
+    pub fn into_indexed(mut self) -> Result<IndexedCar<R>> {
+        let index = match self.index_offset {
+            Some(offset) => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                read_multihash_index_sorted(&mut self.file)?
+            }
+            None => {
+                let mut index: BTreeMap<(u64, usize), Vec<u8>> = BTreeMap::new();
+                let mut blocks = self.blocks()?;
+                while let Some((offset, cid, _)) = blocks.next_section()? {
+                    let hash = cid.hash();
+                    let entries = index.entry((hash.code(), hash.digest().len())).or_default();
+                    entries.extend_from_slice(hash.digest());
+                    entries.extend_from_slice(&offset.to_le_bytes());
+                }
+                sort_index(index)
+            }
+        };
+
+        Ok(IndexedCar {
+            reader: self,
+            index,
+        })
+    }
+
+    /// Read the section at `position`, returning its CID, its block and the position after it
+    fn read_section(&mut self, position: u64) -> Result<(Cid, Vec<u8>, u64)> {
+        self.file.seek(SeekFrom::Start(position))?;
+        let length = check_section_size(unsigned_varint::io::read_u64(&mut self.file)?)?;
+        let start = self.file.stream_position()?;
+        let end = start + length;
+        if end > self.data_end {
+            return Err(anyhow!("CAR section at {} is truncated", position));
+        }
+
+        let mut section = vec![0; length as usize];
+        self.file.read_exact(&mut section)?;
+
+        let (cid, block) = split_section(section, self.verify)?;
+        Ok((cid, block, end))
+    }
+}
+
+/// Split a section into its CID and block, checking the block against the CID if `verify` is set
+fn split_section(mut section: Vec<u8>, verify: bool) -> Result<(Cid, Vec<u8>)> {
+    let mut cursor = Cursor::new(&section);
+    let cid = Cid::read_bytes(&mut cursor)?;
+    let block = section.split_off(cursor.position() as usize);
+
+    if verify {
+        let code = Code::try_from(cid.hash().code())?;
+        if code.digest(&block) != *cid.hash() {
+            return Err(anyhow!("Block {} does not match its CID", cid));
+        }
+    }
+
+    Ok((cid, block))
+}
+
+fn check_section_size(length: u64) -> Result<u64> {
+    match length > MAX_SECTION_SIZE {
+        true => Err(anyhow!(
+            "CAR section of {} bytes is over the limit of {}",
+            length,
+            MAX_SECTION_SIZE
+        )),
+        false => Ok(length),
+    }
+}
+
+fn check_header_size(length: u64) -> Result<u64> {
+    match length > MAX_HEADER_SIZE {
+        true => Err(anyhow!(
+            "CAR header of {} bytes is over the limit of {}",
+            length,
+            MAX_HEADER_SIZE
+        )),
+        false => Ok(length),
+    }
+}
+
+fn read_header<R: Read>(file: &mut R) -> Result<(Vec<Cid>, u64)> {
+    let length = check_header_size(unsigned_varint::io::read_u64(&mut *file)?)?;
+    let mut header = vec![0; length as usize];
+    file.read_exact(&mut header)?;
+    decode_header(&header)
+}
+
+fn decode_header(header: &[u8]) -> Result<(Vec<Cid>, u64)> {
+    let mut d = minicbor::Decoder::new(header);
+    let entries = d
+        .map()?
+        .ok_or_else(|| anyhow!("CAR header must have a definite length"))?;
+
+    let mut roots = vec![];
+    let mut version = None;
+    for _ in 0..entries {
+        match d.str()? {
+            "roots" => {
+                for root in d.array_iter::<crate::Cid>()? {
+                    roots.push(root?.0);
+                }
+            }
+            "version" => version = Some(d.u64()?),
+            _ => d.skip()?,
+        }
+    }
+
+    Ok((
+        roots,
+        version.ok_or_else(|| anyhow!("CAR header has no version"))?,
+    ))
+}
+
+/// Iterator over the `(cid, block)` sections of a CAR
+pub struct Blocks<'a, R: Read + Seek> {
+    reader: &'a mut CarReader<R>,
+    position: u64,
+}
+
+impl<R: Read + Seek> Blocks<'_, R> {
+    /// Read the next section along with its offset in the CARv1 payload
+    fn next_section(&mut self) -> Result<Option<(u64, Cid, Vec<u8>)>> {
+        if self.position >= self.reader.data_end {
+            return Ok(None);
+        }
+
+        let offset = self.position - self.reader.data_offset;
+        let (cid, block, end) = self.reader.read_section(self.position)?;
+        self.position = end;
+        Ok(Some((offset, cid, block)))
+    }
+}
+
+impl<R: Read + Seek> Iterator for Blocks<'_, R> {
+    type Item = Result<(Cid, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_section() {
+            Ok(section) => section.map(|(_, cid, block)| Ok((cid, block))),
+            Err(e) => {
+                // Stop after an error rather than trying to read garbage
+                self.position = self.reader.data_end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A CAR that blocks can be read from by CID without scanning it.
+pub struct IndexedCar<R: Read + Seek> {
+    reader: CarReader<R>,
+    /// Sorted `digest || offset` entries, grouped by multihash code and digest length
+    index: BTreeMap<(u64, usize), Vec<u8>>,
+}
+
+impl<R: Read + Seek> IndexedCar<R> {
+    pub fn reader(&mut self) -> &mut CarReader<R> {
+        &mut self.reader
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.offset(cid).is_some()
+    }
+
+    pub fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let offset = match self.offset(cid) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let (found, block, _) = self.reader.read_section(self.reader.data_offset + offset)?;
+        if found.hash() != cid.hash() {
+            return Err(anyhow!("CAR index points {} at block {}", cid, found));
+        }
+        Ok(Some(block))
+    }
+
+    fn offset(&self, cid: &Cid) -> Option<u64> {
+        let hash = cid.hash();
+        let digest = hash.digest();
+        let entries = self.index.get(&(hash.code(), digest.len()))?;
+
+        let width = digest.len() + 8;
+        let (mut low, mut high) = (0, entries.len() / width);
+        while low < high {
+            let mid = (low + high) / 2;
+            let entry = &entries[mid * width..(mid + 1) * width];
+            match entry[..digest.len()].cmp(digest) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => {
+                    return Some(u64::from_le_bytes(
+                        entry[digest.len()..].try_into().unwrap(),
+                    ))
+                }
+            }
+        }
+        None
+    }
+}
+
+/// An indexed CAR can serve queries directly, for example to re-serve a tree CAR.
+impl<R: Read + Seek + Send> BlockSource for Mutex<IndexedCar<R>> {
+    fn get<'a>(&'a self, cid: &'a crate::Cid) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            self.lock()
+                .unwrap()
+                .get(&cid.0)?
+                .ok_or_else(|| anyhow!("Block {} not found", cid))
+        })
+    }
+}
+
+fn sort_index(index: BTreeMap<(u64, usize), Vec<u8>>) -> BTreeMap<(u64, usize), Vec<u8>> {
+    index
+        .into_iter()
+        .map(|((code, digest_len), entries)| {
+            let mut sorted: Vec<&[u8]> = entries.chunks_exact(digest_len + 8).collect();
+            sorted.sort_unstable();
+            ((code, digest_len), sorted.concat())
+        })
+        .collect()
+}
+
+/// Read an index written by `write_multihash_index_sorted`, or by go-car.
+fn read_multihash_index_sorted<R: Read>(file: &mut R) -> Result<BTreeMap<(u64, usize), Vec<u8>>> {
+    let codec = unsigned_varint::io::read_usize(&mut *file)?;
+    if codec != MULTIHASH_INDEX_SORTED {
+        return Err(anyhow!("Unsupported CAR index codec {:#x}", codec));
+    }
+
+    let mut index = BTreeMap::new();
+    for _ in 0..i32::from_le_bytes(read_le::<4, _>(file)?) {
+        let code = u64::from_le_bytes(read_le::<8, _>(file)?);
+        for _ in 0..u32::from_le_bytes(read_le::<4, _>(file)?) {
+            let width = u32::from_le_bytes(read_le::<4, _>(file)?) as usize;
+            let length = u64::from_le_bytes(read_le::<8, _>(file)?);
+            if width <= 8 || length % width as u64 != 0 {
+                return Err(anyhow!("CAR index has invalid width {}", width));
+            }
+
+            let mut entries = vec![];
+            file.take(length).read_to_end(&mut entries)?;
+            if entries.len() as u64 != length {
+                return Err(anyhow!("CAR index is truncated"));
+            }
+            index.insert((code, width - 8), entries);
+        }
+    }
+
+    // Entries written by other tools are not guaranteed to be sorted
+    Ok(sort_index(index))
+}
+
+fn read_le<const N: usize, R: Read>(file: &mut R) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// An async counterpart to `Car`, writing a CARv1 to a tokio `AsyncWrite`.
+pub struct AsyncCar<W: AsyncWrite + Unpin> {
+    header: Vec<u8>,
+    placeholder_root: bool,
+    file: W,
+    position: u64,
+    dedup: Option<Dedup>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncCar<W> {
+    /// Start a CARv1 with `roots` in its header, using a placeholder root like `Car::new`.
+    pub fn new(file: W, roots: Vec<Cid>) -> Self {
+        let (header, placeholder_root) = encode_roots(roots);
+
+        AsyncCar {
+            header,
+            placeholder_root,
+            file,
+            position: 0,
+            dedup: None,
+        }
+    }
+
+    /// Skip blocks whose CID is already in `seen`.
+    pub fn with_dedup(mut self, seen: SeenSet) -> Self {
+        self.dedup = Some(Dedup::new(seen));
+        self
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.dedup.as_ref().map_or(0, |dedup| dedup.duplicates)
+    }
+
+    pub async fn encode_header(&mut self) -> Result<()> {
+        let mut buffer = usize_buffer();
+        let length = usize(self.header.len(), &mut buffer);
+        self.file.write_all(length).await?;
+        self.file.write_all(&self.header).await?;
+        self.position += (length.len() + self.header.len()) as u64;
+
+        if self.placeholder_root {
+            self.write_block_cid(&placeholder_root(), EMPTY_MAP_BLOCK)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write_block_cid(&mut self, cid: &Cid, block: &[u8]) -> Result<()> {
+        self.write_block(&cid.to_bytes(), block).await
+    }
+
+    pub async fn write_block(&mut self, cid: &[u8], block: &[u8]) -> Result<()> {
+        if is_duplicate(&mut self.dedup, cid)? {
+            return Ok(());
+        }
+
+        let mut buffer = usize_buffer();
+        let length = usize(cid.len() + block.len(), &mut buffer);
+        self.file.write_all(length).await?;
+        self.file.write_all(cid).await?;
+        self.file.write_all(block).await?;
+
+        self.position += (length.len() + cid.len() + block.len()) as u64;
+        Ok(())
+    }
+
+    /// Number of bytes written so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Flush and shut down the writer, so uploads and sockets see the end of the CAR.
+    pub async fn finish(mut self) -> Result<W> {
+        self.file.flush().await?;
+        self.file.shutdown().await?;
+        Ok(self.file)
+    }
+}
+
+/// Reads the blocks of a CARv1 or CARv2 in order from a tokio `AsyncRead`, without seeking.
+///
+/// The index of a CARv2 is not read, as it comes after the blocks. Lengths are read a byte
+/// at a time, so the reader is wrapped in a `BufReader`.
+pub struct CarStream<R: AsyncRead + Unpin> {
+    file: BufReader<R>,
+    roots: Vec<Cid>,
+    version: u64,
+    /// Bytes of block sections left to read, only known for a CARv2
+    remaining: Option<u64>,
+    verify: bool,
+}
+
+impl<R: AsyncRead + Unpin> CarStream<R> {
+    pub async fn new(file: R) -> Result<Self> {
+        let mut file = BufReader::new(file);
+        let (roots, version) = read_header_async(&mut file).await?;
+        match version {
+            1 => Ok(CarStream {
+                file,
+                roots,
+                version,
+                remaining: None,
+                verify: false,
+            }),
+            2 => {
+                let mut header = [0; CARV2_HEADER_LEN];
+                file.read_exact(&mut header).await?;
+                let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+                let (data_offset, data_size) = (field(16), field(24));
+
+                // Skip any padding between the header and the payload
+                let padding = data_offset
+                    .checked_sub((CARV2_PRAGMA.len() + CARV2_HEADER_LEN) as u64)
+                    .ok_or_else(|| anyhow!("CARv2 payload overlaps its header"))?;
+                tokio::io::copy(&mut (&mut file).take(padding), &mut tokio::io::sink()).await?;
+
+                let (roots, inner_version, header_len) = read_v1_header_async(&mut file).await?;
+                if inner_version != 1 {
+                    return Err(anyhow!("CARv2 payload has version {}", inner_version));
+                }
+
+                Ok(CarStream {
+                    file,
+                    roots,
+                    version,
+                    remaining: Some(
+                        data_size
+                            .checked_sub(header_len)
+                            .ok_or_else(|| anyhow!("CARv2 payload is truncated"))?,
+                    ),
+                    verify: false,
+                })
+            }
+            _ => Err(anyhow!("Unsupported CAR version {}", version)),
+        }
+    }
+
+    /// Check every block read against the hash in its CID.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Read the next block, or `None` at the end of the CAR.
+    pub async fn next_block(&mut self) -> Result<Option<(Cid, Vec<u8>)>> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+
+        let (length, varint_len) = match read_varint_async(&mut self.file).await? {
+            Some(varint) => varint,
+            None if self.remaining.is_none() => return Ok(None),
+            None => return Err(anyhow!("CARv2 payload is truncated")),
+        };
+        check_section_size(length)?;
+
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining
+                .checked_sub(varint_len + length)
+                .ok_or_else(|| anyhow!("CAR section is longer than the payload"))?;
+        }
+
+        let mut section = vec![0; length as usize];
+        self.file.read_exact(&mut section).await?;
+        split_section(section, self.verify).map(Some)
+    }
+
+    /// The blocks as a `Stream`, ending after the first error.
+    pub fn into_stream(self) -> impl Stream<Item = Result<(Cid, Vec<u8>)>> {
+        stream::try_unfold(self, |mut car| async move {
+            Ok(car.next_block().await?.map(|block| (block, car)))
+        })
+    }
+}
+
+async fn read_header_async<R: AsyncRead + Unpin>(file: &mut R) -> Result<(Vec<Cid>, u64)> {
+    read_v1_header_async(file)
+        .await
+        .map(|(roots, version, _)| (roots, version))
+}
+
+/// Read a CARv1 style header, also returning the number of bytes it took up
+async fn read_v1_header_async<R: AsyncRead + Unpin>(file: &mut R) -> Result<(Vec<Cid>, u64, u64)> {
+    let (length, varint_len) = read_varint_async(file)
+        .await?
+        .ok_or_else(|| anyhow!("CAR is empty"))?;
+
+    let mut header = vec![0; check_header_size(length)? as usize];
+    file.read_exact(&mut header).await?;
+    let (roots, version) = decode_header(&header)?;
+    Ok((roots, version, varint_len + length))
+}
+
+/// Read an unsigned varint along with its length in bytes, or `None` at the end of the input
+async fn read_varint_async<R: AsyncRead + Unpin>(file: &mut R) -> Result<Option<(u64, u64)>> {
+    let mut buffer = unsigned_varint::encode::u64_buffer();
+    for i in 0..buffer.len() {
+        match file.read(&mut buffer[i..=i]).await? {
+            0 if i == 0 => return Ok(None),
+            0 => return Err(anyhow!("CAR ends in the middle of a varint")),
+            _ => {}
+        }
+
+        if unsigned_varint::decode::is_last(buffer[i]) {
+            let (value, _) = unsigned_varint::decode::u64(&buffer[..=i])?;
+            return Ok(Some((value, i as u64 + 1)));
+        }
+    }
+    Err(anyhow!("CAR varint is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{cbor::DagCborCodec, codec::Codec, ipld, Ipld};
+
+    fn block(data: &[u8]) -> (Cid, Vec<u8>) {
+        (
+            Cid::new_v1(0x55, Code::Sha2_256.digest(data)),
+            data.to_vec(),
+        )
+    }
+
+    fn blocks() -> Vec<(Cid, Vec<u8>)> {
+        vec![block(b"one"), block(b"two"), block(b"three")]
+    }
+
+    /// The decoded header of a CARv1 and the sections after it
+    fn split_header(file: &[u8]) -> (Ipld, &[u8]) {
+        let (length, rest) = unsigned_varint::decode::usize(file).unwrap();
+        (
+            DagCborCodec.decode(&rest[..length]).unwrap(),
+            &rest[length..],
+        )
+    }
+
+    #[test]
+    fn header_holds_the_given_roots() {
+        let blocks = blocks();
+        let mut car = Car::new(vec![], vec![blocks[0].0, blocks[1].0]);
+        car.encode_header().unwrap();
+        let file = car.into_inner();
+
+        let (header, rest) = split_header(&file);
+        assert_eq!(
+            header,
+            ipld!({"roots": [blocks[0].0, blocks[1].0], "version": 1})
+        );
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn header_without_roots_uses_the_placeholder() {
+        let mut car = Car::new(vec![], vec![]);
+        car.encode_header().unwrap();
+        let file = car.into_inner();
+
+        let (header, rest) = split_header(&file);
+        assert_eq!(header, ipld!({"roots": [placeholder_root()], "version": 1}));
+
+        // The placeholder block follows the header
+        let root = placeholder_root().to_bytes();
+        let mut section = usize(root.len() + 1, &mut usize_buffer()).to_vec();
+        section.extend_from_slice(&root);
+        section.extend_from_slice(EMPTY_MAP_BLOCK);
+        assert_eq!(rest, section);
+    }
+
+    #[test]
+    fn carv2_wraps_the_carv1_payload() {
+        let blocks = blocks();
+        let mut v1 = Car::new(vec![], vec![blocks[0].0]);
+        let mut v2 = CarV2::new(Cursor::new(vec![]), vec![blocks[0].0]).unwrap();
+        v1.encode_header().unwrap();
+        v2.encode_header().unwrap();
+        for (cid, data) in &blocks {
+            v1.write_block_cid(cid, data).unwrap();
+            v2.write_block_cid(cid, data).unwrap();
+        }
+        let v1 = v1.into_inner();
+        let v2 = v2.finish().unwrap().into_inner();
+
+        assert_eq!(&v2[..CARV2_PRAGMA.len()], CARV2_PRAGMA);
+        let header = &v2[CARV2_PRAGMA.len()..CARV2_PRAGMA.len() + CARV2_HEADER_LEN];
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap()) as usize;
+        assert_eq!(header[..16], [0; 16]);
+        assert_eq!(field(16), CARV2_PRAGMA.len() + CARV2_HEADER_LEN);
+        assert_eq!(field(24), v1.len());
+        assert_eq!(field(32), field(16) + field(24));
+
+        assert_eq!(&v2[field(16)..field(32)], &v1[..]);
+        let (codec, _) = unsigned_varint::decode::usize(&v2[field(32)..]).unwrap();
+        assert_eq!(codec, MULTIHASH_INDEX_SORTED);
+    }
+
+    #[test]
+    fn carv2_index_round_trip() {
+        let blocks = blocks();
+        let mut car = CarV2::new(Cursor::new(vec![]), vec![blocks[0].0]).unwrap();
+        car.encode_header().unwrap();
+        for (cid, data) in &blocks {
+            car.write_block_cid(cid, data).unwrap();
+        }
+        let file = car.finish().unwrap();
+
+        let reader = CarReader::new(Cursor::new(file.into_inner())).unwrap();
+        assert_eq!(reader.version(), 2);
+        assert_eq!(reader.roots(), &[blocks[0].0]);
+
+        let mut indexed = reader.verify(true).into_indexed().unwrap();
+        for (cid, data) in &blocks {
+            assert_eq!(indexed.get(cid).unwrap().as_ref(), Some(data));
+        }
+        assert_eq!(indexed.get(&block(b"four").0).unwrap(), None);
+
+        let read: Vec<_> = indexed
+            .reader()
+            .blocks()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(read, blocks);
+    }
+
+    #[test]
+    fn carv1_index_is_built_by_scanning() {
+        let blocks = blocks();
+        let mut car = Car::new(vec![], vec![]);
+        car.encode_header().unwrap();
+        for (cid, data) in &blocks {
+            car.write_block_cid(cid, data).unwrap();
+        }
+
+        let reader = CarReader::new(Cursor::new(car.into_inner())).unwrap();
+        assert_eq!(reader.version(), 1);
+        assert_eq!(reader.roots(), &[placeholder_root()]);
+
+        let mut indexed = reader.into_indexed().unwrap();
+        assert!(indexed.contains(&placeholder_root()));
+        for (cid, data) in &blocks {
+            assert_eq!(indexed.get(cid).unwrap().as_ref(), Some(data));
+        }
+    }
+
+    #[test]
+    fn index_written_out_of_order_is_sorted() {
+        let blocks = blocks();
+        let mut index: BTreeMap<(u64, usize), Vec<u8>> = BTreeMap::new();
+        for (offset, (cid, _)) in blocks.iter().enumerate().rev() {
+            let entries = index.entry((0x12, 32)).or_default();
+            entries.extend_from_slice(cid.hash().digest());
+            entries.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+
+        let mut file = usize(MULTIHASH_INDEX_SORTED, &mut usize_buffer()).to_vec();
+        write_multihash_index_sorted(&mut file, index).unwrap();
+        let read = read_multihash_index_sorted(&mut Cursor::new(file)).unwrap();
+
+        let entries = &read[&(0x12, 32)];
+        let digests: Vec<_> = entries.chunks_exact(40).map(|e| &e[..32]).collect();
+        let mut sorted = digests.clone();
+        sorted.sort_unstable();
+        assert_eq!(digests, sorted);
+    }
+
+    #[test]
+    fn carv2_header_past_the_end_is_rejected() {
+        let mut car = CarV2::new(Cursor::new(vec![]), vec![]).unwrap();
+        car.encode_header().unwrap();
+        let file = car.finish().unwrap().into_inner();
+        let header = CARV2_PRAGMA.len();
+
+        let crafted = |field: usize, value: u64| {
+            let mut file = file.clone();
+            file[header + field..header + field + 8].copy_from_slice(&value.to_le_bytes());
+            CarReader::new(Cursor::new(file))
+        };
+
+        assert!(crafted(24, file.len() as u64).is_err());
+        assert!(crafted(24, u64::MAX).is_err());
+        assert!(crafted(16, u64::MAX).is_err());
+        assert!(crafted(32, file.len() as u64 + 1).is_err());
+        assert!(crafted(24, 0).is_ok());
+    }
+
+    #[test]
+    fn dedup_skips_blocks_already_written() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for seen in [SeenSet::memory(), SeenSet::disk((*db).clone())] {
+            let blocks = blocks();
+            let mut car = Car::new(vec![], vec![blocks[0].0]).with_dedup(seen);
+            car.encode_header().unwrap();
+            for (cid, data) in blocks.iter().chain(&blocks).chain(&blocks[..1]) {
+                car.write_block_cid(cid, data).unwrap();
+            }
+            assert_eq!(car.duplicates(), 4);
+
+            let mut expected = Car::new(vec![], vec![blocks[0].0]);
+            expected.encode_header().unwrap();
+            for (cid, data) in &blocks {
+                expected.write_block_cid(cid, data).unwrap();
+            }
+            assert_eq!(car.into_inner(), expected.into_inner());
+        }
+    }
+
+    #[test]
+    fn dedup_leaves_duplicates_out_of_the_carv2_index() {
+        let blocks = blocks();
+        let mut car = CarV2::new(Cursor::new(vec![]), vec![])
+            .unwrap()
+            .with_dedup(SeenSet::memory());
+        car.encode_header().unwrap();
+        for (cid, data) in blocks.iter().chain(&blocks) {
+            car.write_block_cid(cid, data).unwrap();
+        }
+        let file = car.finish().unwrap().into_inner();
+
+        let reader = CarReader::new(Cursor::new(file)).unwrap();
+        let mut indexed = reader.into_indexed().unwrap();
+        let read: Vec<_> = indexed
+            .reader()
+            .blocks()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        // The placeholder root and each block once
+        assert_eq!(read.len(), blocks.len() + 1);
+        for (cid, data) in &blocks {
+            assert_eq!(indexed.get(cid).unwrap().as_ref(), Some(data));
+        }
+    }
+
+    #[test]
+    fn depth_first_writes_parents_before_children() {
+        let mut map = crate::IpldHashMap::new(2, 1);
+        for i in 0..200u32 {
+            let key = i.to_be_bytes();
+            let value = crate::Cid(block(&key).0);
+            map.set(Box::new(key), value).unwrap();
+        }
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let root = map.collapse(&tree);
+
+        let mut car = Car::new(vec![], vec![root.0]);
+        car.encode_header().unwrap();
+        let count = write_tree_depth_first(&tree, &root.0, &mut car).unwrap();
+        assert_eq!(count as usize, tree.len());
+
+        let mut reader = CarReader::new(Cursor::new(car.into_inner()))
+            .unwrap()
+            .verify(true);
+        let read: Vec<_> = reader.blocks().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(read.len(), tree.len());
+        assert_eq!(read[0].0, root.0);
+
+        // Every node is linked from a node written before it
+        let mut seen = HashSet::new();
+        for (i, (cid, block)) in read.iter().enumerate() {
+            assert!(i == 0 || seen.contains(cid));
+            let children = query::child_nodes(block, i == 0).unwrap();
+            seen.extend(children.into_iter().map(|child| child.0));
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_are_rejected() {
+        let mut header = vec![];
+        header.extend_from_slice(usize(MAX_HEADER_SIZE as usize + 1, &mut usize_buffer()));
+        let error = CarReader::new(Cursor::new(header)).err().unwrap();
+        assert!(error.to_string().contains("over the limit"));
+
+        let mut car = Car::new(vec![], vec![]);
+        car.encode_header().unwrap();
+        let mut file = car.into_inner();
+        file.extend_from_slice(usize(MAX_SECTION_SIZE as usize + 1, &mut usize_buffer()));
+        let mut reader = CarReader::new(Cursor::new(file)).unwrap();
+        let error = reader.blocks().unwrap().nth(1).unwrap().unwrap_err();
+        assert!(error.to_string().contains("over the limit"));
+    }
+
+    fn split_dir(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("hamt-split-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn write_split(directory: &Path, limit: SplitLimit, blocks: &[(Cid, Vec<u8>)]) -> Manifest {
+        let mut car = SplitCar::new(&directory.join("tree.car"), vec![blocks[0].0], limit);
+        car.encode_header().unwrap();
+        for (cid, data) in blocks {
+            car.write_block_cid(cid, data).unwrap();
+        }
+        car.finish().unwrap()
+    }
+
+    fn read_volume(directory: &Path, volume: &Volume) -> (Vec<Cid>, Vec<(Cid, Vec<u8>)>) {
+        let file = std::fs::read(directory.join(&volume.file)).unwrap();
+        assert_eq!(file.len() as u64, volume.bytes);
+        let mut reader = CarReader::new(Cursor::new(file)).unwrap();
+        let blocks = reader.blocks().unwrap().collect::<Result<_>>().unwrap();
+        (reader.roots().to_vec(), blocks)
+    }
+
+    #[test]
+    fn split_by_blocks_keeps_roots_in_their_volume() {
+        let directory = split_dir("blocks");
+        let blocks: Vec<_> = (0..5u8).map(|i| block(&[i])).collect();
+        let limit = SplitLimit {
+            bytes: None,
+            blocks: Some(2),
+        };
+        let manifest = write_split(&directory, limit, &blocks);
+
+        assert_eq!(manifest.roots, vec![blocks[0].0.to_string()]);
+        assert_eq!(manifest.volumes.len(), 3);
+        let placeholder = (placeholder_root(), EMPTY_MAP_BLOCK.to_vec());
+        for (i, volume) in manifest.volumes.iter().enumerate() {
+            let (roots, read) = read_volume(&directory, volume);
+            let mut expected = blocks.chunks(2).nth(i).unwrap().to_vec();
+            if i == 0 {
+                assert_eq!(roots, vec![blocks[0].0]);
+                assert_eq!(volume.roots, manifest.roots);
+            } else {
+                assert_eq!(roots, vec![placeholder_root()]);
+                assert!(volume.roots.is_empty());
+                expected.insert(0, placeholder.clone());
+            }
+            assert_eq!(volume.blocks, 2.min(5 - 2 * i as u64));
+            assert_eq!(read, expected);
+        }
+
+        let files = std::fs::read_dir(&directory).unwrap().count();
+        assert_eq!(files, manifest.volumes.len() + 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn split_by_bytes_stays_under_the_limit() {
+        let directory = split_dir("bytes");
+        let mut blocks: Vec<_> = (0..20u8).map(|i| block(&[i; 40])).collect();
+        blocks.insert(10, block(&[0xff; 400]));
+        let limit = SplitLimit {
+            bytes: Some(300),
+            blocks: None,
+        };
+        let manifest = write_split(&directory, limit, &blocks);
+
+        let mut read = vec![];
+        for volume in &manifest.volumes {
+            if volume.bytes > 300 {
+                assert_eq!(volume.blocks, 1);
+            }
+            let (_, volume_blocks) = read_volume(&directory, volume);
+            read.extend(
+                volume_blocks
+                    .into_iter()
+                    .filter(|(cid, _)| *cid != placeholder_root()),
+            );
+        }
+        assert_eq!(read, blocks);
+        assert!(manifest.volumes.iter().any(|volume| volume.bytes > 300));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn car_stream_reads_async_car() {
+        let blocks = blocks();
+        let mut car = AsyncCar::new(vec![], vec![blocks[0].0]);
+        car.encode_header().await.unwrap();
+        for (cid, data) in &blocks {
+            car.write_block_cid(cid, data).await.unwrap();
+        }
+        let file = car.finish().await.unwrap();
+
+        let mut stream = CarStream::new(&file[..]).await.unwrap().verify(true);
+        assert_eq!(stream.roots(), &[blocks[0].0]);
+        let mut read = vec![];
+        while let Some(block) = stream.next_block().await.unwrap() {
+            read.push(block);
+        }
+        assert_eq!(read, blocks);
+    }
+
+    /// Counts the reads made on the underlying reader
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl AsyncRead for CountingReader<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.reads += 1;
+            std::pin::Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn car_stream_buffers_its_reads() {
+        let blocks: Vec<_> = (0..100u32).map(|i| block(&i.to_be_bytes())).collect();
+        let mut car = AsyncCar::new(vec![], vec![]);
+        car.encode_header().await.unwrap();
+        for (cid, data) in &blocks {
+            car.write_block_cid(cid, data).await.unwrap();
+        }
+        let file = car.finish().await.unwrap();
+
+        let reader = CountingReader {
+            data: &file,
+            reads: 0,
+        };
+        let mut stream = CarStream::new(reader).await.unwrap();
+        let mut count = 0;
+        while stream.next_block().await.unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, blocks.len() + 1);
+        // The whole car fits in the buffer, so one read fills it and one finds the end
+        assert!(stream.file.get_ref().reads <= 2);
+    }
+
+    #[tokio::test]
+    async fn car_stream_rejects_oversized_lengths() {
+        let header = usize(MAX_HEADER_SIZE as usize + 1, &mut usize_buffer()).to_vec();
+        let error = CarStream::new(&header[..]).await.err().unwrap();
+        assert!(error.to_string().contains("over the limit"));
+
+        let mut car = Car::new(vec![], vec![]);
+        car.encode_header().unwrap();
+        let mut file = car.into_inner();
+        file.extend_from_slice(usize(MAX_SECTION_SIZE as usize + 1, &mut usize_buffer()));
+        let mut stream = CarStream::new(&file[..]).await.unwrap();
+        stream.next_block().await.unwrap();
+        let error = stream.next_block().await.unwrap_err();
+        assert!(error.to_string().contains("over the limit"));
+    }
 }
@@ -6,7 +6,14 @@ use minicbor::{
     decode, encode, {Decode, Encode},
 };
 
-use std::fmt::Display;
+use crate::dag_cbor::CID_NEWTYPE;
+use multibase::Base;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt::{self, Display},
+    hash::Hash,
+    str::FromStr,
+};
 
 #[derive(Debug, Clone)]
 pub struct Cid(pub ExtCid);
@@ -39,7 +46,7 @@ impl Ord for Cid {
 
 impl PartialOrd for Cid {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 
     fn lt(&self, other: &Self) -> bool {
@@ -67,12 +74,67 @@ impl PartialEq for Cid {
 
 impl Eq for Cid {}
 
+impl Hash for Cid {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Hash::hash(&self.0, state)
+    }
+}
+
 impl Display for Cid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
     }
 }
 
+impl Cid {
+    /// Format the CID with `base` instead of the default base32, or base58btc for a CIDv0.
+    /// A CIDv0 can only be written in base58btc.
+    pub fn to_string_of_base(&self, base: Base) -> Result<String> {
+        Ok(self.0.to_string_of_base(base)?)
+    }
+}
+
+/// Look up a multibase by name, such as `base32` or `base58btc`, for command line options
+pub fn parse_base(name: &str) -> Result<Base, String> {
+    match name {
+        "base16" => Ok(Base::Base16Lower),
+        "base32" => Ok(Base::Base32Lower),
+        "base32upper" => Ok(Base::Base32Upper),
+        "base36" => Ok(Base::Base36Lower),
+        "base58btc" => Ok(Base::Base58Btc),
+        "base64" => Ok(Base::Base64),
+        "base64url" => Ok(Base::Base64Url),
+        _ => Err(format!(
+            "Unknown multibase {}, expected base16, base32, base32upper, base36, base58btc, base64 or base64url",
+            name
+        )),
+    }
+}
+
+/// Parses a CID in any multibase, or a base58btc CIDv0
+impl FromStr for Cid {
+    type Err = ::cid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExtCid::try_from(s).map(Cid)
+    }
+}
+
+/// Parses the binary form of a CID, without the multibase prefix used in dag-cbor
+impl TryFrom<&[u8]> for Cid {
+    type Error = ::cid::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        ExtCid::try_from(bytes).map(Cid)
+    }
+}
+
+impl From<ExtCid> for Cid {
+    fn from(cid: ExtCid) -> Self {
+        Cid(cid)
+    }
+}
+
 impl Encode for Cid {
     fn encode<W: encode::Write>(
         &self,
@@ -87,16 +149,401 @@ impl Encode for Cid {
     }
 }
 
+/// Decodes a tag 42 link of any CID version and codec, use `CidDecoder` to restrict them
 impl Decode<'_> for Cid {
     fn decode(d: &mut minicbor::Decoder<'_>) -> Result<Self, decode::Error> {
         let tag = d.tag()?;
         if tag != Tag::Unassigned(42) {
-            return Err(decode::Error::TypeMismatch(Type::Tag, "Unknown tag found!"));
+            return Err(decode::Error::TypeMismatch(
+                Type::Tag,
+                "a link must have tag 42",
+            ));
+        }
+        parse_link(d.bytes()?).map_err(|e| decode::Error::Message(e.message()))
+    }
+}
+
+/// dag-pb, the codec of every CIDv0
+pub const DAG_PB: u64 = 0x70;
+/// dag-cbor
+pub const DAG_CBOR: u64 = 0x71;
+/// raw binary
+pub const RAW: u64 = 0x55;
+
+/// Why the bytes of a link are not an acceptable CID
+#[derive(Debug, thiserror::Error)]
+pub enum CidError {
+    #[error("Link is empty, it must start with the 0x00 multibase prefix")]
+    MissingPrefix,
+    #[error("Link starts with {0:#04x} instead of the 0x00 multibase prefix")]
+    WrongPrefix(u8),
+    #[error("CID version {0} is not supported")]
+    UnsupportedVersion(u64),
+    #[error("CIDv0 links are not allowed in strict mode")]
+    Version0,
+    #[error("CID codec {0:#x} is not allowed")]
+    CodecNotAllowed(u64),
+    #[error("Invalid CID: {0}")]
+    Invalid(::cid::Error),
+}
+
+impl CidError {
+    /// A fixed message for minicbor errors, which cannot hold the details
+    fn message(&self) -> &'static str {
+        match self {
+            CidError::MissingPrefix => "Link is missing its multibase prefix",
+            CidError::WrongPrefix(_) => "Link does not start with the 0x00 multibase prefix",
+            CidError::UnsupportedVersion(_) => "CID version is not supported",
+            CidError::Version0 => "CIDv0 links are not allowed",
+            CidError::CodecNotAllowed(_) => "CID codec is not allowed",
+            CidError::Invalid(_) => "Invalid CID",
+        }
+    }
+}
+
+/// Strip the 0x00 multibase prefix from the bytes of a tag 42 link
+pub(crate) fn strip_prefix(bytes: &[u8]) -> Result<&[u8], CidError> {
+    match bytes.split_first() {
+        Some((0, cid)) => Ok(cid),
+        Some((&prefix, _)) => Err(CidError::WrongPrefix(prefix)),
+        None => Err(CidError::MissingPrefix),
+    }
+}
+
+/// Parse the bytes of a tag 42 link, checking the prefix and that the CID is v0 or v1
+fn parse_link(bytes: &[u8]) -> Result<Cid, CidError> {
+    let bytes = strip_prefix(bytes)?;
+    // A CIDv0 is a bare sha2-256 multihash, anything else starts with its version
+    if !bytes.starts_with(&[0x12, 0x20]) {
+        match unsigned_varint::decode::u64(bytes) {
+            Ok((1, _)) => {}
+            Ok((version, _)) => return Err(CidError::UnsupportedVersion(version)),
+            Err(_) => return Err(CidError::Invalid(::cid::Error::VarIntDecodeError)),
+        }
+    }
+    Cid::try_from(bytes).map_err(CidError::Invalid)
+}
+
+/// Which links to accept when decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CidMode {
+    /// Only CIDv1 links with an allowed codec
+    Strict,
+    /// Also CIDv0 links, which are always dag-pb, such as UnixFS files added with older
+    /// IPFS versions
+    Lenient,
+}
+
+impl FromStr for CidMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(CidMode::Strict),
+            "lenient" => Ok(CidMode::Lenient),
+            _ => Err(format!(
+                "Unknown CID mode {}, expected strict or lenient",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for CidMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CidMode::Strict => f.write_str("strict"),
+            CidMode::Lenient => f.write_str("lenient"),
+        }
+    }
+}
+
+/// Checks links against a `CidMode` and a set of allowed codecs
+#[derive(Debug, Clone)]
+pub struct CidDecoder {
+    mode: CidMode,
+    codecs: Vec<u64>,
+}
+
+impl CidDecoder {
+    /// Allows dag-cbor and raw links, and dag-pb as well in lenient mode
+    pub fn new(mode: CidMode) -> Self {
+        let codecs = match mode {
+            CidMode::Strict => vec![DAG_CBOR, RAW],
+            CidMode::Lenient => vec![DAG_CBOR, RAW, DAG_PB],
+        };
+        CidDecoder { mode, codecs }
+    }
+
+    /// Replace the allowed codecs. CIDv0 links are still accepted in lenient mode.
+    pub fn with_codecs(mut self, codecs: Vec<u64>) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    pub fn mode(&self) -> CidMode {
+        self.mode
+    }
+
+    /// Decode a tag 42 link and check it
+    pub fn decode(&self, d: &mut minicbor::Decoder<'_>) -> Result<Cid> {
+        if d.tag()? != Tag::Unassigned(42) {
+            return Err(anyhow::anyhow!("A link must have tag 42"));
+        }
+        self.parse(d.bytes()?)
+    }
+
+    /// Parse and check the bytes of a tag 42 link, including the multibase prefix
+    pub fn parse(&self, bytes: &[u8]) -> Result<Cid> {
+        let cid = parse_link(bytes)?;
+        self.check(&cid)?;
+        Ok(cid)
+    }
+
+    pub fn check(&self, cid: &Cid) -> Result<(), CidError> {
+        if cid.0.version() == ::cid::Version::V0 {
+            return match self.mode {
+                CidMode::Strict => Err(CidError::Version0),
+                CidMode::Lenient => Ok(()),
+            };
         }
-        let cid = ExtCid::read_bytes(&d.bytes()?[1..]);
-        match cid {
-            Ok(cid) => Ok(Cid(cid)),
-            Err(_) => Err(decode::Error::Message("Could not parse invalid CID")),
+        match self.codecs.contains(&cid.0.codec()) {
+            true => Ok(()),
+            false => Err(CidError::CodecNotAllowed(cid.0.codec())),
+        }
+    }
+}
+
+/// Serialized as a string in human readable formats like JSON, otherwise as a newtype around
+/// the CID bytes, which `dag_cbor` writes as a tag 42 link.
+impl Serialize for Cid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.collect_str(self),
+            false => {
+                serializer.serialize_newtype_struct(CID_NEWTYPE, &CidBytes(&self.0.to_bytes()))
+            }
+        }
+    }
+}
+
+struct CidBytes<'a>(&'a [u8]);
+
+impl Serialize for CidBytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Also accepts the DAG-JSON link form `{"/": "<cid>"}` in human readable formats
+impl<'de> Deserialize<'de> for Cid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(CidVisitor),
+            false => deserializer.deserialize_newtype_struct(CID_NEWTYPE, CidVisitor),
+        }
+    }
+}
+
+struct CidVisitor;
+
+impl<'de> de::Visitor<'de> for CidVisitor {
+    type Value = Cid;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a CID")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Cid, D::Error> {
+        d.deserialize_bytes(self)
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Cid, E> {
+        Cid::try_from(bytes).map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Cid, E> {
+        s.parse().map_err(E::custom)
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Cid, A::Error> {
+        let cid = match map.next_entry::<String, String>()? {
+            Some((key, cid)) if key == "/" => cid,
+            _ => {
+                return Err(de::Error::custom(
+                    "expected a link of the form {\"/\": \"<cid>\"}",
+                ))
+            }
+        };
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::custom("a link must only have the \"/\" key"));
+        }
+        self.visit_str(&cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multihash::{Code, MultihashDigest};
+
+    fn link(cid: &ExtCid) -> Vec<u8> {
+        [&[0], &cid.to_bytes()[..]].concat()
+    }
+
+    fn v1(codec: u64) -> ExtCid {
+        ExtCid::new_v1(codec, Code::Sha2_256.digest(b"block"))
+    }
+
+    fn v0() -> ExtCid {
+        ExtCid::new_v0(Code::Sha2_256.digest(b"block")).unwrap()
+    }
+
+    fn error(decoder: &CidDecoder, bytes: &[u8]) -> CidError {
+        decoder
+            .parse(bytes)
+            .unwrap_err()
+            .downcast::<CidError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn strict_allows_dag_cbor_and_raw_v1() {
+        let strict = CidDecoder::new(CidMode::Strict);
+        for codec in [DAG_CBOR, RAW] {
+            assert_eq!(strict.parse(&link(&v1(codec))).unwrap().0, v1(codec));
+        }
+        assert!(matches!(
+            error(&strict, &link(&v1(DAG_PB))),
+            CidError::CodecNotAllowed(DAG_PB)
+        ));
+        assert!(matches!(error(&strict, &link(&v0())), CidError::Version0));
+    }
+
+    #[test]
+    fn lenient_also_allows_dag_pb_and_v0() {
+        let lenient = CidDecoder::new(CidMode::Lenient);
+        assert_eq!(lenient.parse(&link(&v0())).unwrap().0, v0());
+        assert_eq!(lenient.parse(&link(&v1(DAG_PB))).unwrap().0, v1(DAG_PB));
+        assert!(matches!(
+            error(&lenient, &link(&v1(0x0129))),
+            CidError::CodecNotAllowed(0x0129)
+        ));
+    }
+
+    #[test]
+    fn codecs_can_be_replaced() {
+        let raw_only = CidDecoder::new(CidMode::Lenient).with_codecs(vec![RAW]);
+        assert!(raw_only.parse(&link(&v1(RAW))).is_ok());
+        assert!(raw_only.parse(&link(&v1(DAG_CBOR))).is_err());
+        // v0 is implicitly dag-pb, but lenient mode still accepts it
+        assert!(raw_only.parse(&link(&v0())).is_ok());
+    }
+
+    #[test]
+    fn malformed_links_are_rejected() {
+        let lenient = CidDecoder::new(CidMode::Lenient);
+        let cid = v1(DAG_CBOR).to_bytes();
+        assert!(matches!(error(&lenient, &[]), CidError::MissingPrefix));
+        assert!(matches!(
+            error(&lenient, &[&[0x01], &cid[..]].concat()),
+            CidError::WrongPrefix(0x01)
+        ));
+        assert!(matches!(
+            error(&lenient, &[0x00, 0x02, 0x71]),
+            CidError::UnsupportedVersion(2)
+        ));
+        assert!(matches!(
+            error(&lenient, &link(&v1(DAG_CBOR))[..10]),
+            CidError::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn decode_checks_the_tag() {
+        let strict = CidDecoder::new(CidMode::Strict);
+        let cid = Cid(v1(DAG_CBOR));
+        let block = minicbor::to_vec(&cid).unwrap();
+        let decoded = strict.decode(&mut minicbor::Decoder::new(&block)).unwrap();
+        assert_eq!(decoded, cid);
+
+        let mut wrong_tag = block.clone();
+        wrong_tag[1] = 43;
+        assert!(strict
+            .decode(&mut minicbor::Decoder::new(&wrong_tag))
+            .is_err());
+        assert!(minicbor::decode::<Cid>(&wrong_tag).is_err());
+    }
+
+    #[test]
+    fn strings_round_trip_in_base32_and_base58btc() {
+        let cid = Cid(v1(DAG_CBOR));
+        let base32 = cid.to_string();
+        assert!(base32.starts_with('b'));
+        assert_eq!(base32, cid.to_string_of_base(Base::Base32Lower).unwrap());
+        assert_eq!(base32.parse::<Cid>().unwrap(), cid);
+
+        let base58 = cid.to_string_of_base(Base::Base58Btc).unwrap();
+        assert!(base58.starts_with('z'));
+        assert_eq!(base58.parse::<Cid>().unwrap(), cid);
+
+        // A CIDv0 is bare base58btc and has no other string form
+        let v0 = Cid(v0());
+        assert!(v0.to_string().starts_with("Qm"));
+        assert_eq!(v0.to_string().parse::<Cid>().unwrap(), v0);
+        assert!(v0.to_string_of_base(Base::Base32Lower).is_err());
+    }
+
+    #[test]
+    fn invalid_strings_and_bytes_are_rejected() {
+        let base32 = Cid(v1(DAG_CBOR)).to_string();
+        // Unknown multibase prefix, bad base32 characters, truncated and empty
+        assert!(matches!(
+            format!("!{}", &base32[1..]).parse::<Cid>(),
+            Err(::cid::Error::ParsingError)
+        ));
+        assert!("b0189".parse::<Cid>().is_err());
+        assert!(base32[..20].parse::<Cid>().is_err());
+        assert!("".parse::<Cid>().is_err());
+
+        let bytes = v1(DAG_CBOR).to_bytes();
+        assert_eq!(Cid::try_from(&bytes[..]).unwrap().0, v1(DAG_CBOR));
+        assert!(Cid::try_from(&bytes[..10]).is_err());
+        // The 0x00 multibase prefix of a dag-cbor link is not part of the CID bytes
+        assert!(Cid::try_from(&link(&v1(DAG_CBOR))[..]).is_err());
+    }
+
+    #[test]
+    fn serde_uses_strings_in_json_and_links_in_dag_cbor() {
+        let cid = Cid(v1(RAW));
+        let json = serde_json::to_string(&cid).unwrap();
+        assert_eq!(json, format!("\"{}\"", cid));
+        assert_eq!(serde_json::from_str::<Cid>(&json).unwrap(), cid);
+
+        let link = format!("{{\"/\": \"{}\"}}", cid);
+        assert_eq!(serde_json::from_str::<Cid>(&link).unwrap(), cid);
+        assert!(serde_json::from_str::<Cid>(&format!("{{\"cid\": \"{}\"}}", cid)).is_err());
+        assert!(serde_json::from_str::<Cid>(&format!("{{\"/\": \"{}\", \"x\": 1}}", cid)).is_err());
+        assert!(serde_json::from_str::<Cid>("\"not a cid\"").is_err());
+
+        let block = crate::dag_cbor::to_vec(&cid).unwrap();
+        assert_eq!(block, minicbor::to_vec(&cid).unwrap());
+        assert_eq!(crate::dag_cbor::from_block::<Cid>(&block).unwrap(), cid);
+    }
+
+    #[test]
+    fn bases_parse_by_name() {
+        assert_eq!(parse_base("base32").unwrap(), Base::Base32Lower);
+        assert_eq!(parse_base("base58btc").unwrap(), Base::Base58Btc);
+        assert_eq!(parse_base("base64url").unwrap(), Base::Base64Url);
+        assert!(parse_base("base58").is_err());
+    }
+
+    #[test]
+    fn modes_parse_and_display() {
+        for mode in [CidMode::Strict, CidMode::Lenient] {
+            assert_eq!(mode.to_string().parse::<CidMode>().unwrap(), mode);
         }
+        assert!("loose".parse::<CidMode>().is_err());
     }
 }
@@ -0,0 +1,1090 @@
+//! A serde `Serializer` and `Deserializer` for DAG-CBOR, so typed records can be written as
+//! blocks and read back without going through JSON.
+//!
+//! Output follows the strict DAG-CBOR rules: definite lengths, map keys that are strings
+//! ordered by length and then bytewise, minimal integers and 64 bit floats. `Cid` fields are
+//! written as tag 42 links.
+
+use crate::{
+    value::{encoder, MAX_DEPTH},
+    Cid,
+};
+use ::cid::Cid as ExtCid;
+use minicbor::data::{Tag, Type};
+use multihash::{Code, MultihashDigest};
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer},
+    ser::{self, Serialize},
+    Deserialize,
+};
+use std::fmt;
+
+/// Name of the newtype `Cid` serializes as, so this serializer can write it as a tag 42 link
+pub(crate) const CID_NEWTYPE: &str = "$hamt_rs::Cid";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Message(String),
+    #[error("Map keys must be strings")]
+    KeyMustBeString,
+    #[error("Duplicate map key {0}")]
+    DuplicateKey(String),
+    #[error("DAG-CBOR does not allow NaN or infinite floats")]
+    NonFiniteFloat,
+    #[error("Integer {0} does not fit in 64 bits")]
+    IntegerTooLarge(String),
+    #[error("Block has {0} bytes left over after the value")]
+    TrailingBytes(usize),
+    #[error("Could not encode value: {0}")]
+    Encode(#[from] minicbor::encode::Error<std::io::Error>),
+    #[error("Could not decode block: {0}")]
+    Decode(#[from] minicbor::decode::Error),
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Serialize `value` as a DAG-CBOR block.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut serializer = Serializer::default();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_inner())
+}
+
+/// Serialize `value` as a DAG-CBOR block along with its sha2-256 CID, the same way
+/// `hamt ingest` writes records.
+pub fn to_block<T: Serialize + ?Sized>(value: &T) -> Result<(Cid, Vec<u8>)> {
+    let block = to_vec(value)?;
+    // 0x71 - dag_cbor
+    let cid = ExtCid::new_v1(0x71, Code::Sha2_256.digest(&block));
+    Ok((Cid(cid), block))
+}
+
+/// Deserialize a DAG-CBOR block, which must hold exactly one value.
+pub fn from_slice<'de, T: Deserialize<'de>>(block: &'de [u8]) -> Result<T> {
+    let mut deserializer = Deserializer::new(block);
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Like `from_slice`, for types that do not borrow from the block
+pub fn from_block<T: DeserializeOwned>(block: &[u8]) -> Result<T> {
+    from_slice(block)
+}
+
+#[derive(Debug, Default)]
+pub struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = SeqSerializer<'a>;
+    type SerializeTuple = SeqSerializer<'a>;
+    type SerializeTupleStruct = SeqSerializer<'a>;
+    type SerializeTupleVariant = SeqSerializer<'a>;
+    type SerializeMap = MapSerializer<'a>;
+    type SerializeStruct = MapSerializer<'a>;
+    type SerializeStructVariant = MapSerializer<'a>;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        encoder(&mut self.out).bool(v)?;
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        encoder(&mut self.out).i64(v)?;
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        if let Ok(v) = u64::try_from(v) {
+            self.serialize_u64(v)
+        } else if let Ok(v) = i64::try_from(v) {
+            self.serialize_i64(v)
+        } else if let Ok(n) = u64::try_from(-1 - v) {
+            // minicbor cannot write integers below i64::MIN, which always take 8 bytes
+            self.out.push(0x3b);
+            self.out.extend_from_slice(&n.to_be_bytes());
+            Ok(())
+        } else {
+            Err(Error::IntegerTooLarge(v.to_string()))
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        encoder(&mut self.out).u64(v)?;
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        match u64::try_from(v) {
+            Ok(v) => self.serialize_u64(v),
+            Err(_) => Err(Error::IntegerTooLarge(v.to_string())),
+        }
+    }
+
+    /// DAG-CBOR only allows 64 bit floats
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        if !v.is_finite() {
+            return Err(Error::NonFiniteFloat);
+        }
+        encoder(&mut self.out).f64(v)?;
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        encoder(&mut self.out).str(v)?;
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        encoder(&mut self.out).bytes(v)?;
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        encoder(&mut self.out).null()?;
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        match name {
+            CID_NEWTYPE => {
+                let mut inner = Serializer::default();
+                value.serialize(&mut inner)?;
+                let bytes = minicbor::Decoder::new(&inner.out)
+                    .bytes()
+                    .map_err(|_| Error::Message("A CID must serialize as bytes".into()))?;
+
+                // Prefixed with the multibase '0' to signify binary encoding
+                let prefixed = [&[0], bytes].concat();
+                encoder(&mut self.out)
+                    .tag(Tag::Unassigned(42))?
+                    .bytes(&prefixed)?;
+                Ok(())
+            }
+            _ => value.serialize(self),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        encoder(&mut self.out).map(1)?.str(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqSerializer<'a>> {
+        Ok(SeqSerializer::new(self, None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SeqSerializer<'a>> {
+        Ok(SeqSerializer::new(self, None))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SeqSerializer<'a>> {
+        Ok(SeqSerializer::new(self, None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SeqSerializer<'a>> {
+        Ok(SeqSerializer::new(self, Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer<'a>> {
+        Ok(MapSerializer::new(self, None))
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<MapSerializer<'a>> {
+        let mut map = MapSerializer::new(self, None);
+        map.number = name == JSON_NUMBER;
+        Ok(map)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapSerializer<'a>> {
+        Ok(MapSerializer::new(self, Some(variant)))
+    }
+}
+
+/// Name of the struct `serde_json::Number` serializes as with arbitrary_precision enabled
+const JSON_NUMBER: &str = "$serde_json::private::Number";
+
+/// Write a JSON number as an integer if it is written as one, otherwise as a float.
+/// Integers that do not fit in 64 bits are an error rather than rounded.
+fn write_json_number(serializer: &mut Serializer, text: &str) -> Result<()> {
+    use ser::Serializer as _;
+
+    if text.contains(['.', 'e', 'E']) {
+        let n = text
+            .parse::<f64>()
+            .map_err(|_| Error::Message(format!("Invalid JSON number {}", text)))?;
+        serializer.serialize_f64(n)
+    } else {
+        match text.parse::<i128>() {
+            Ok(n) => serializer.serialize_i128(n),
+            Err(_) => Err(Error::IntegerTooLarge(text.to_string())),
+        }
+    }
+}
+
+/// Buffers the elements of a sequence, as its length may not be known up front and
+/// DAG-CBOR does not allow indefinite lengths.
+pub struct SeqSerializer<'a> {
+    serializer: &'a mut Serializer,
+    /// Set for tuple variants, which are written as `{variant: [...]}`
+    variant: Option<&'static str>,
+    elements: Serializer,
+    count: u64,
+}
+
+impl<'a> SeqSerializer<'a> {
+    fn new(serializer: &'a mut Serializer, variant: Option<&'static str>) -> Self {
+        SeqSerializer {
+            serializer,
+            variant,
+            elements: Serializer::default(),
+            count: 0,
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut self.elements)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        let out = &mut self.serializer.out;
+        if let Some(variant) = self.variant {
+            encoder(out).map(1)?.str(variant)?;
+        }
+        encoder(out).array(self.count)?;
+        out.extend_from_slice(&self.elements.out);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Buffers the entries of a map so they can be written in DAG-CBOR key order.
+pub struct MapSerializer<'a> {
+    serializer: &'a mut Serializer,
+    /// Set for struct variants, which are written as `{variant: {...}}`
+    variant: Option<&'static str>,
+    /// Encoded keys and values
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+    /// Set for `serde_json::Number`, which is a struct holding the number's text
+    number: bool,
+}
+
+impl<'a> MapSerializer<'a> {
+    fn new(serializer: &'a mut Serializer, variant: Option<&'static str>) -> Self {
+        MapSerializer {
+            serializer,
+            variant,
+            entries: vec![],
+            key: None,
+            number: false,
+        }
+    }
+
+    fn entry<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<()> {
+        let mut serializer = Serializer::default();
+        value.serialize(&mut serializer)?;
+        self.entries.push((key, serializer.out));
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.number {
+            let text: String = match self.entries.pop() {
+                Some((_, value)) => minicbor::decode(&value)?,
+                None => return Err(Error::Message("Empty JSON number".into())),
+            };
+            return write_json_number(self.serializer, &text);
+        }
+
+        // Keys are encoded strings, so comparing the encodings orders by length and then bytewise
+        self.entries
+            .sort_unstable_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+        if let Some(pair) = self.entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            let key: String = minicbor::decode(&pair[0].0)?;
+            return Err(Error::DuplicateKey(key));
+        }
+
+        let out = &mut self.serializer.out;
+        if let Some(variant) = self.variant {
+            encoder(out).map(1)?.str(variant)?;
+        }
+        encoder(out).map(self.entries.len() as u64)?;
+        for (key, value) in self.entries {
+            out.extend_from_slice(&key);
+            out.extend_from_slice(&value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let mut out = vec![];
+        key.serialize(KeySerializer(&mut out))?;
+        self.key = Some(out);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::Message("Map value serialized before its key".to_string()))?;
+        self.entry(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        let mut out = vec![];
+        encoder(&mut out).str(key)?;
+        self.entry(out, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Serializes map keys, which DAG-CBOR requires to be strings
+struct KeySerializer<'a>(&'a mut Vec<u8>);
+
+macro_rules! reject_key {
+    ($($method:ident($($arg:ty),*)),* $(,)?) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<()> {
+            Err(Error::KeyMustBeString)
+        })*
+    };
+}
+
+impl ser::Serializer for KeySerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<(), Error>;
+    type SerializeTuple = ser::Impossible<(), Error>;
+    type SerializeTupleStruct = ser::Impossible<(), Error>;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = ser::Impossible<(), Error>;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        encoder(self.0).str(v)?;
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    reject_key!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+    );
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<()> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::KeyMustBeString)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::KeyMustBeString)
+    }
+}
+
+pub struct Deserializer<'de> {
+    d: minicbor::Decoder<'de>,
+    block: &'de [u8],
+    /// Number of arrays and maps around the current value
+    depth: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(block: &'de [u8]) -> Self {
+        Deserializer {
+            d: minicbor::Decoder::new(block),
+            block,
+            depth: 0,
+        }
+    }
+
+    /// Read the contents of an array or map, failing if it is nested too deeply
+    fn nested<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::Message("Value is nested too deeply".into()));
+        }
+        self.depth += 1;
+        let value = read(self)?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    /// Check that the whole block was read
+    pub fn end(&self) -> Result<()> {
+        match self.block.len() - self.d.position() {
+            0 => Ok(()),
+            left => Err(Error::TrailingBytes(left)),
+        }
+    }
+
+    /// The bytes of a tag 42 link, without the multibase prefix
+    fn cid_bytes(&mut self) -> Result<&'de [u8]> {
+        if self.d.tag()? != Tag::Unassigned(42) {
+            return Err(Error::Message("Only tag 42 is allowed in dag-cbor".into()));
+        }
+        crate::cid::strip_prefix(self.d.bytes()?).map_err(|e| Error::Message(e.to_string()))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.d.datatype()? {
+            Type::Bool => visitor.visit_bool(self.d.bool()?),
+            Type::Null => {
+                self.d.null()?;
+                visitor.visit_unit()
+            }
+            Type::U8 | Type::U16 | Type::U32 | Type::U64 => visitor.visit_u64(self.d.u64()?),
+            Type::I8 | Type::I16 | Type::I32 => visitor.visit_i64(self.d.i64()?),
+            Type::I64 => {
+                // minicbor cannot read integers below i64::MIN, which DAG-CBOR allows
+                let position = self.d.position();
+                let argument = self
+                    .block
+                    .get(position + 1..position + 9)
+                    .ok_or(minicbor::decode::Error::EndOfInput)?;
+                self.d.set_position(position + 9);
+                let n = u64::from_be_bytes(argument.try_into().unwrap());
+                match i64::try_from(n) {
+                    Ok(n) => visitor.visit_i64(-1 - n),
+                    Err(_) => visitor.visit_i128(-1 - n as i128),
+                }
+            }
+            Type::F64 => visitor.visit_f64(self.d.f64()?),
+            Type::Bytes => visitor.visit_borrowed_bytes(self.d.bytes()?),
+            Type::String => visitor.visit_borrowed_str(self.d.str()?),
+            Type::Array => {
+                let length = self.d.array()?.ok_or_else(indefinite)?;
+                self.nested(|de| {
+                    visitor.visit_seq(Access {
+                        de,
+                        remaining: length,
+                    })
+                })
+            }
+            Type::Map => {
+                let length = self.d.map()?.ok_or_else(indefinite)?;
+                self.nested(|de| {
+                    visitor.visit_map(Access {
+                        de,
+                        remaining: length,
+                    })
+                })
+            }
+            // Links read as anything other than a `Cid` are their bytes
+            Type::Tag => visitor.visit_borrowed_bytes(self.cid_bytes()?),
+            other => Err(Error::Message(format!(
+                "Type {} is not allowed in dag-cbor",
+                other
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.d.datatype()? {
+            Type::Null => {
+                self.d.null()?;
+                visitor.visit_none()
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        match name {
+            CID_NEWTYPE => {
+                let bytes = self.cid_bytes()?;
+                visitor.visit_newtype_struct(BorrowedBytes(bytes))
+            }
+            _ => visitor.visit_newtype_struct(self),
+        }
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.d.datatype()? {
+            Type::String => visitor.visit_enum(self.d.str()?.into_deserializer()),
+            Type::Map => {
+                if self.d.map()? != Some(1) {
+                    return Err(Error::Message(
+                        "Enum variants with data must be a map of one entry".into(),
+                    ));
+                }
+                self.nested(|de| visitor.visit_enum(de))
+            }
+            other => Err(Error::Message(format!("Expected an enum, found {}", other))),
+        }
+    }
+
+    /// Ignored values are still checked, so a block is rejected the same whatever type it
+    /// is read as
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_any(&mut *self, de::IgnoredAny)?;
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+fn indefinite() -> Error {
+    Error::Message("DAG-CBOR does not allow indefinite lengths".into())
+}
+
+/// Reads the elements of an array or the entries of a map
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: u64,
+}
+
+impl<'de> de::SeqAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        usize::try_from(self.remaining).ok()
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Access<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        if self.de.d.datatype()? != Type::String {
+            return Err(Error::KeyMustBeString);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        usize::try_from(self.remaining).ok()
+    }
+}
+
+/// Enums with data, written as `{variant: value}`
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(&mut *self)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Hands the bytes of a link to `Cid`'s visitor
+struct BorrowedBytes<'de>(&'de [u8]);
+
+impl<'de> de::Deserializer<'de> for BorrowedBytes<'de> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Empty,
+        Count(u32),
+        Pair(i8, bool),
+        Named { label: String },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        name: String,
+        big: u64,
+        small: i64,
+        wide: i128,
+        ratio: f64,
+        missing: Option<u32>,
+        kinds: Vec<Kind>,
+        scores: BTreeMap<String, Vec<u8>>,
+    }
+
+    fn record() -> Record {
+        Record {
+            name: "record".to_string(),
+            big: u64::MAX,
+            small: i64::MIN,
+            wide: -(1 << 64),
+            ratio: 0.25,
+            missing: None,
+            kinds: vec![
+                Kind::Empty,
+                Kind::Count(7),
+                Kind::Pair(-1, true),
+                Kind::Named {
+                    label: "x".to_string(),
+                },
+            ],
+            scores: [("bb", vec![1]), ("a", vec![]), ("ab", vec![2, 3])]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let block = to_vec(&record()).unwrap();
+        assert_eq!(from_block::<Record>(&block).unwrap(), record());
+    }
+
+    // The i128 field can only become a serde_json::Number with arbitrary precision
+    #[test]
+    #[cfg(feature = "arbitrary-precision")]
+    fn matches_strict_value_encoding() {
+        let record = record();
+        let json = serde_json::to_value(&record).unwrap();
+        let strict = Value(json).to_dag_cbor(crate::Encoding::Strict).unwrap();
+        assert_eq!(to_vec(&record).unwrap(), strict);
+    }
+
+    #[test]
+    fn links_round_trip() {
+        let (cid, _) = to_block("linked").unwrap();
+        let block = to_vec(&vec![cid.clone()]).unwrap();
+        assert_eq!(&block[..4], &[0x81, 0xd8, 0x2a, 0x58]);
+        let read: Vec<Cid> = from_slice(&block).unwrap();
+        assert_eq!(read[0].0, cid.0);
+    }
+
+    #[test]
+    fn floats_are_always_64_bits() {
+        assert_eq!(to_vec(&1.5f32).unwrap(), to_vec(&1.5f64).unwrap());
+        assert_eq!(to_vec(&1.5f64).unwrap()[0], 0xfb);
+        assert!(matches!(to_vec(&f64::NAN), Err(Error::NonFiniteFloat)));
+        assert!(matches!(to_vec(&f64::INFINITY), Err(Error::NonFiniteFloat)));
+    }
+
+    #[test]
+    fn integers_outside_64_bits_are_rejected() {
+        assert!(to_vec(&(-(1i128 << 64) - 1)).is_err());
+        assert!(to_vec(&(u64::MAX as u128 + 1)).is_err());
+    }
+
+    #[test]
+    fn json_numbers_are_integers_unless_written_as_floats() {
+        let write = |text: &str| {
+            let mut serializer = Serializer::default();
+            write_json_number(&mut serializer, text).map(|_| serializer.into_inner())
+        };
+        assert_eq!(
+            write("18446744073709551615").unwrap(),
+            to_vec(&u64::MAX).unwrap()
+        );
+        assert_eq!(
+            write("-18446744073709551616").unwrap(),
+            to_vec(&-(1i128 << 64)).unwrap()
+        );
+        assert_eq!(write("1.0").unwrap(), to_vec(&1.0f64).unwrap());
+        assert_eq!(write("1e3").unwrap(), to_vec(&1000.0f64).unwrap());
+        // Too wide for 64 bits, within i128 and past it
+        for text in [
+            "18446744073709551616",
+            "-18446744073709551617",
+            "170141183460469231731687303715884105728",
+            "-1000000000000000000000000000000000000000000",
+        ] {
+            assert!(
+                matches!(write(text), Err(Error::IntegerTooLarge(n)) if n == text),
+                "{}",
+                text
+            );
+        }
+        assert!(matches!(write("1e400"), Err(Error::NonFiniteFloat)));
+    }
+
+    #[test]
+    fn non_dag_cbor_values_are_rejected() {
+        // undefined, f16 1.0, f32 1.0
+        for block in [
+            &[0xf7][..],
+            &[0xf9, 0x3c, 0x00],
+            &[0xfa, 0x3f, 0x80, 0x00, 0x00],
+        ] {
+            assert!(from_slice::<Option<f64>>(block).is_err());
+            assert!(from_slice::<de::IgnoredAny>(block).is_err());
+        }
+        // An indefinite length array
+        assert!(from_slice::<Vec<u8>>(&[0x9f, 0x01, 0xff]).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut block = to_vec(&record()).unwrap();
+        block.push(0xf6);
+        assert!(matches!(
+            from_block::<Record>(&block),
+            Err(Error::TrailingBytes(1))
+        ));
+        assert!(Value::from_dag_cbor(&block).is_err());
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let nested = |depth: usize| {
+            let mut block = vec![0x81; depth];
+            block.push(0x00);
+            block
+        };
+        assert!(from_slice::<de::IgnoredAny>(&nested(MAX_DEPTH)).is_ok());
+        assert!(from_slice::<de::IgnoredAny>(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(from_slice::<serde_json::Value>(&nested(100_000)).is_err());
+    }
+}
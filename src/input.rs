@@ -0,0 +1,514 @@
+use anyhow::{anyhow, Result};
+use serde::de::{Deserializer as _, SeqAccess, Visitor};
+use serde_json::{Map, Value as JsonValue};
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read},
+    str::FromStr,
+    sync::mpsc::{self, SyncSender},
+    thread,
+};
+
+/// Number of parsed elements of a JSON array buffered ahead of the workers
+const JSON_ARRAY_BUFFER: usize = 1024;
+
+/// Layout of a dataset to ingest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Tab separated columns, without quoting, so records can hold JSON as is
+    Tsv,
+    /// Comma separated columns with the usual double quote escaping
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+    /// A single JSON array of objects, read one element at a time
+    Json,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(InputFormat::Tsv),
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(format!(
+                "Unknown input format {}, expected tsv, csv, jsonl or json",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputFormat::Tsv => f.write_str("tsv"),
+            InputFormat::Csv => f.write_str("csv"),
+            InputFormat::JsonLines => f.write_str("jsonl"),
+            InputFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// A column by its position counting from 1, or by its name in the header row.
+///
+/// Text that is a number is always a position, so a column named `2022` is written as
+/// `name:2022`. For the JSON formats a name is a field of each object, or a JSON pointer if it starts
+/// with `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("name:") {
+            return Ok(Column::Name(name.to_string()));
+        }
+        match s.parse::<usize>() {
+            Ok(0) => Err("Columns are counted from 1".to_string()),
+            Ok(index) => Ok(Column::Index(index)),
+            Err(_) => Ok(Column::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Index(index) => write!(f, "{}", index),
+            Column::Name(name) => {
+                match name.parse::<usize>().is_ok() || name.starts_with("name:") {
+                    true => write!(f, "name:{}", name),
+                    false => f.write_str(name),
+                }
+            }
+        }
+    }
+}
+
+/// Where the key and record of each entry are found in a dataset.
+///
+/// Datasets are read with `open`, which splits the input into rows on the calling thread.
+/// The rows are then turned into entries with `Parser::parse`, which can run in parallel.
+#[derive(Debug, Clone)]
+pub struct Input {
+    format: InputFormat,
+    key: Option<Column>,
+    record: Option<Column>,
+    header: bool,
+}
+
+impl Input {
+    /// TSV defaults to the key in the 2nd column and the JSON record in the 5th. The other
+    /// formats need a key column, and default to the whole object as the record for JSON or
+    /// an object of every column for CSV with a header.
+    pub fn new(format: InputFormat) -> Self {
+        Input {
+            format,
+            key: None,
+            record: None,
+            header: false,
+        }
+    }
+
+    pub fn with_key(mut self, key: Column) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// The column holding the record as JSON text, or for the JSON formats the field
+    /// holding the record.
+    pub fn with_record(mut self, record: Column) -> Self {
+        self.record = Some(record);
+        self
+    }
+
+    /// Read the first row of a TSV or CSV as column names instead of an entry
+    pub fn with_header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn format(&self) -> InputFormat {
+        self.format
+    }
+
+    pub fn open<R: Read + Send + 'static>(&self, reader: R) -> Result<(Rows, Parser)> {
+        let (key, record) = match (self.format, &self.key, &self.record) {
+            (InputFormat::Tsv, key, record) => (
+                key.clone().unwrap_or(Column::Index(2)),
+                Some(record.clone().unwrap_or(Column::Index(5))),
+            ),
+            (_, Some(key), record) => (key.clone(), record.clone()),
+            (format, None, _) => {
+                return Err(anyhow!("A key column is needed for {} input", format))
+            }
+        };
+
+        match self.format {
+            InputFormat::Tsv | InputFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(match self.format {
+                        InputFormat::Tsv => b'\t',
+                        _ => b',',
+                    })
+                    .quoting(self.format == InputFormat::Csv)
+                    .has_headers(self.header)
+                    .flexible(true)
+                    .from_reader(reader);
+
+                let headers: Option<Vec<String>> = match self.header {
+                    true => Some(reader.headers()?.iter().map(str::to_string).collect()),
+                    false => None,
+                };
+                let index = |column: &Column| -> Result<usize> {
+                    match (column, &headers) {
+                        (Column::Index(index), _) => Ok(index - 1),
+                        (Column::Name(name), Some(headers)) => headers
+                            .iter()
+                            .position(|header| header == name)
+                            .ok_or_else(|| anyhow!("No column is named {}", name)),
+                        (Column::Name(name), None) => Err(anyhow!(
+                            "Column {} is a name, which needs a header row",
+                            name
+                        )),
+                    }
+                };
+
+                let key = Field::Column(index(&key)?);
+                let record = match &record {
+                    Some(record) => Field::Column(index(record)?),
+                    None => Field::Headers(headers.clone().ok_or_else(|| {
+                        anyhow!("A record column is needed without a header row")
+                    })?),
+                };
+                let parser = Parser { key, record };
+                let rows = reader.into_records().map(|row| Ok(Row::Columns(row?)));
+                Ok((Rows(Box::new(rows)), parser))
+            }
+            InputFormat::JsonLines | InputFormat::Json => {
+                let field = |column: Column| -> Result<String> {
+                    match column {
+                        Column::Name(name) => Ok(name),
+                        Column::Index(index) => Err(anyhow!(
+                            "Column {} is a position, JSON input needs a field name",
+                            index
+                        )),
+                    }
+                };
+                let parser = Parser {
+                    key: Field::Name(field(key)?),
+                    record: match record {
+                        Some(record) => Field::Name(field(record)?),
+                        None => Field::Whole,
+                    },
+                };
+
+                let rows: Box<dyn Iterator<Item = Result<Row>> + Send> = match self.format {
+                    InputFormat::JsonLines => Box::new(
+                        BufReader::new(reader)
+                            .lines()
+                            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                            .map(|line| Ok(Row::Line(line?))),
+                    ),
+                    _ => Box::new(json_array(reader)),
+                };
+                Ok((Rows(rows), parser))
+            }
+        }
+    }
+}
+
+/// The rows of a dataset, before they are parsed
+pub struct Rows(Box<dyn Iterator<Item = Result<Row>> + Send>);
+
+impl Iterator for Rows {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+/// A row of a dataset, which `Parser::parse` turns into an entry
+pub enum Row {
+    Columns(csv::StringRecord),
+    Line(String),
+    Object(JsonValue),
+}
+
+enum Field {
+    Column(usize),
+    /// An object of every column under these names
+    Headers(Vec<String>),
+    Name(String),
+    /// The whole object
+    Whole,
+}
+
+/// Finds the key and record in each row of a dataset
+pub struct Parser {
+    key: Field,
+    record: Field,
+}
+
+impl Parser {
+    pub fn parse(&self, row: Row) -> Result<(String, JsonValue)> {
+        match row {
+            Row::Columns(columns) => {
+                let column = |index: usize| {
+                    columns
+                        .get(index)
+                        .ok_or_else(|| anyhow!("Row has no column {}", index + 1))
+                };
+                let key = match self.key {
+                    Field::Column(index) => column(index)?.to_string(),
+                    _ => unreachable!("keys of delimited rows are columns"),
+                };
+                let record = match &self.record {
+                    Field::Column(index) => serde_json::from_str(column(*index)?)
+                        .map_err(|e| anyhow!("Record {} is not valid JSON: {}", key, e))?,
+                    Field::Headers(headers) => {
+                        if columns.len() != headers.len() {
+                            return Err(anyhow!(
+                                "Row {} has {} columns but the header has {}",
+                                key,
+                                columns.len(),
+                                headers.len()
+                            ));
+                        }
+                        JsonValue::Object(
+                            headers
+                                .iter()
+                                .zip(columns.iter())
+                                .map(|(name, value)| (name.clone(), JsonValue::from(value)))
+                                .collect::<Map<_, _>>(),
+                        )
+                    }
+                    _ => unreachable!("records of delimited rows are columns"),
+                };
+                Ok((key, record))
+            }
+            Row::Line(line) => self.parse(Row::Object(serde_json::from_str(&line)?)),
+            Row::Object(object) => {
+                let key = match &self.key {
+                    Field::Name(name) => match lookup(&object, name) {
+                        Some(JsonValue::String(key)) => key.clone(),
+                        Some(JsonValue::Number(key)) => key.to_string(),
+                        Some(_) => return Err(anyhow!("Key {} is not a string or number", name)),
+                        None => return Err(anyhow!("Object has no key {}", name)),
+                    },
+                    _ => unreachable!("keys of objects are fields"),
+                };
+                let record = match &self.record {
+                    Field::Name(name) => lookup(&object, name)
+                        .cloned()
+                        .ok_or_else(|| anyhow!("Object {} has no record {}", key, name))?,
+                    _ => object,
+                };
+                Ok((key, record))
+            }
+        }
+    }
+}
+
+/// A field of `object`, or the value at a JSON pointer
+fn lookup<'a>(object: &'a JsonValue, name: &str) -> Option<&'a JsonValue> {
+    match name.starts_with('/') {
+        true => object.pointer(name),
+        false => object.get(name),
+    }
+}
+
+/// Parse the elements of a JSON array on another thread, so the whole array is never in
+/// memory at once
+fn json_array<R: Read + Send + 'static>(reader: R) -> impl Iterator<Item = Result<Row>> {
+    let (tx, rx) = mpsc::sync_channel(JSON_ARRAY_BUFFER);
+    thread::spawn(move || {
+        let mut de = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        let result = (&mut de)
+            .deserialize_seq(ArrayVisitor(&tx))
+            .and_then(|_| de.end());
+        if let Err(e) = result {
+            let _ = tx.send(Err(e.into()));
+        }
+    });
+    rx.into_iter()
+}
+
+struct ArrayVisitor<'a>(&'a SyncSender<Result<Row>>);
+
+impl<'de> Visitor<'de> for ArrayVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(object) = seq.next_element()? {
+            // Stop reading if nothing is consuming the rows anymore
+            if self.0.send(Ok(Row::Object(object))).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entries(input: Input, text: &str) -> Result<Vec<(String, JsonValue)>> {
+        let (rows, parser) = input.open(std::io::Cursor::new(text.to_string()))?;
+        rows.map(|row| parser.parse(row?)).collect()
+    }
+
+    fn column(s: &str) -> Column {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn columns_are_counted_from_one() {
+        assert_eq!(column("3"), Column::Index(3));
+        assert_eq!(column("id"), Column::Name("id".to_string()));
+        assert!("0".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn numeric_names_need_the_name_prefix() {
+        assert_eq!(column("name:2022"), Column::Name("2022".to_string()));
+        assert_eq!(column("name:name:x"), Column::Name("name:x".to_string()));
+        for name in ["2022", "id", "name:x"] {
+            let named = Column::Name(name.to_string());
+            assert_eq!(column(&named.to_string()), named);
+        }
+
+        let text = "id,2021,2022
+k1,{},[]
+";
+        let input = Input::new(InputFormat::Csv)
+            .with_header(true)
+            .with_key(column("id"))
+            .with_record(column("name:2022"));
+        assert_eq!(
+            entries(input, text).unwrap(),
+            vec![("k1".to_string(), json!([]))]
+        );
+
+        let object = Input::new(InputFormat::JsonLines)
+            .with_key(column("name:2022"))
+            .with_record(column("record"));
+        let read = entries(object, "{\"2022\": \"k1\", \"record\": 1}\n").unwrap();
+        assert_eq!(read, vec![("k1".to_string(), json!(1))]);
+    }
+
+    #[test]
+    fn tsv_defaults_to_key_and_record_columns() {
+        let text = "a\tk1\tb\tc\t{\"x\": \"1,2\"}\na\tk2\tb\tc\t[\"quoted\"]\n";
+        let entries = entries(Input::new(InputFormat::Tsv), text).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("k1".to_string(), json!({"x": "1,2"})),
+                ("k2".to_string(), json!(["quoted"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_needs_a_key_column() {
+        assert!(entries(Input::new(InputFormat::Csv), "a,b\n").is_err());
+        let input = Input::new(InputFormat::Csv)
+            .with_key(column("1"))
+            .with_record(column("2"));
+        let entries = entries(input, "k1,\"{\"\"x\"\": 1}\"\n").unwrap();
+        assert_eq!(entries, vec![("k1".to_string(), json!({"x": 1}))]);
+    }
+
+    #[test]
+    fn header_names_columns() {
+        let text = "id,name,record\nk1,one,{}\nk2,two,[]\n";
+        let input = Input::new(InputFormat::Csv)
+            .with_header(true)
+            .with_key(column("id"))
+            .with_record(column("record"));
+        let read = entries(input, text).unwrap();
+        assert_eq!(read[1], ("k2".to_string(), json!([])));
+
+        let missing = Input::new(InputFormat::Csv)
+            .with_header(true)
+            .with_key(column("key"));
+        assert!(entries(missing, text).is_err());
+
+        let no_header = Input::new(InputFormat::Csv).with_key(column("id"));
+        assert!(entries(no_header, text).is_err());
+    }
+
+    #[test]
+    fn header_without_record_column_builds_objects() {
+        let input = Input::new(InputFormat::Csv)
+            .with_header(true)
+            .with_key(column("id"));
+        let read = entries(input.clone(), "id,name\nk1,one\n").unwrap();
+        assert_eq!(
+            read,
+            vec![("k1".to_string(), json!({"id": "k1", "name": "one"}))]
+        );
+
+        assert!(entries(input.clone(), "id,name\nk1\n").is_err());
+        assert!(entries(input, "id,name\nk1,one,extra\n").is_err());
+    }
+
+    #[test]
+    fn json_lines_look_up_fields_and_pointers() {
+        let text = "{\"meta\": {\"id\": 7}, \"data\": [1]}\n\n{\"meta\": {\"id\": \"b\"}, \"data\": [2]}\n";
+        let input = Input::new(InputFormat::JsonLines)
+            .with_key(column("/meta/id"))
+            .with_record(column("data"));
+        let read = entries(input, text).unwrap();
+        assert_eq!(
+            read,
+            vec![("7".to_string(), json!([1])), ("b".to_string(), json!([2])),]
+        );
+
+        let whole = Input::new(InputFormat::JsonLines).with_key(column("/meta/id"));
+        assert_eq!(
+            entries(whole, text).unwrap()[0].1,
+            json!({"meta": {"id": 7}, "data": [1]})
+        );
+
+        let missing = Input::new(InputFormat::JsonLines).with_key(column("/meta/name"));
+        assert!(entries(missing, text).is_err());
+        let by_index = Input::new(InputFormat::JsonLines).with_key(column("1"));
+        assert!(entries(by_index, text).is_err());
+    }
+
+    #[test]
+    fn json_array_streams_elements() {
+        let input = Input::new(InputFormat::Json).with_key(column("id"));
+        let read = entries(input.clone(), " [{\"id\": \"a\"}, {\"id\": \"b\"}] \n").unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1], ("b".to_string(), json!({"id": "b"})));
+
+        let (rows, _) = input
+            .open(std::io::Cursor::new("[{\"id\": \"a\"}] {}".to_string()))
+            .unwrap();
+        let rows: Vec<_> = rows.collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].is_ok());
+        assert!(rows[1].is_err());
+
+        assert!(entries(input.clone(), "{\"id\": \"a\"}").is_err());
+        assert!(entries(input, "[{\"id\": \"a\"},").is_err());
+    }
+}
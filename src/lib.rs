@@ -1,18 +1,24 @@
+pub mod cache;
 pub mod car;
 mod cid;
+pub mod dag_cbor;
+pub mod input;
 pub mod query;
+pub mod selector;
+pub mod source;
 mod value;
 
-pub use crate::cid::Cid;
+pub use crate::cid::{parse_base, Cid, CidDecoder, CidError, CidMode, DAG_CBOR, DAG_PB, RAW};
 use ::cid::Cid as ExtCid;
 use anyhow::{anyhow, Result};
+pub use multibase;
 
 use bitvec::prelude::*;
 use minicbor::{encode, Encode};
 use multihash::{Code, MultihashDigest};
-use sled::Tree;
+use sled::{Db, Tree};
 
-pub use value::Value;
+pub use value::{Encoding, NumberPolicy, NumberStats, Value, ValueRef};
 
 #[derive(Debug)]
 pub struct IpldHashMap {
@@ -58,7 +64,7 @@ impl Node {
         let index = &digest[offset..(offset + opts.width)];
         let index = to_int(index);
 
-        match self.elements.get(index).map(Option::as_ref).flatten() {
+        match self.elements.get(index).and_then(Option::as_ref) {
             Some(e) => match e {
                 Element::Node(n) => n.get(key, digest, depth + 1, opts),
                 Element::Bucket(b) => match b.binary_search_by(|v| key.cmp(&v.0)) {
@@ -84,7 +90,7 @@ impl Node {
 
         let _test = self.elements.get_mut(index);
 
-        match self.elements.get_mut(index).map(|x| x.as_mut()).flatten() {
+        match self.elements.get_mut(index).and_then(|x| x.as_mut()) {
             Some(e) => match e {
                 Element::Node(n) => n.set(key, value, digest, depth + 1, opts),
                 Element::Bucket(b) => match b.binary_search_by(|v| key.cmp(&v.0)) {
@@ -111,8 +117,8 @@ impl Node {
                 },
             },
             None => {
-                self.elements
-                    .insert(index, Some(Element::Bucket(vec![(key, value, digest)])));
+                // Replace the empty slot, inserting would shift every later child
+                self.elements[index] = Some(Element::Bucket(vec![(key, value, digest)]));
                 Ok(())
             }
         }
@@ -198,12 +204,15 @@ impl Encode for Options {
         &self,
         e: &mut minicbor::Encoder<W>,
     ) -> Result<(), encode::Error<W::Error>> {
-        e.map(3)?;
+        // The root node is appended after this, so "hamt" has to be the last key
+        e.map(4)?;
         e.str("hashAlg")?;
         let base: u64 = self.hash_alg.into();
         e.encode(base)?;
+        e.str("bitWidth")?;
+        e.encode(self.width)?;
         e.str("bucketSize")?;
-        e.encode(2usize.pow(self.width.try_into().unwrap()))?;
+        e.encode(self.bucket_size)?;
         e.encode("hamt")?;
         Ok(())
     }
@@ -296,6 +305,23 @@ impl IpldHashMap {
     }
 }
 
+/// Name of the sled tree in the tree db that records the root CID of the built HAMT
+const META_TREE: &str = "meta";
+
+pub fn save_root(tree_db: &Db, root: &Cid) -> Result<()> {
+    tree_db
+        .open_tree(META_TREE)?
+        .insert("root", root.0.to_bytes())?;
+    Ok(())
+}
+
+pub fn load_root(tree_db: &Db) -> Result<Option<Cid>> {
+    match tree_db.open_tree(META_TREE)?.get("root")? {
+        Some(root) => Ok(Some(Cid(ExtCid::try_from(root.as_ref())?))),
+        None => Ok(None),
+    }
+}
+
 pub fn to_int(slice: &BitSlice<Msb0, u8>) -> usize {
     // https://www.reddit.com/r/rust/comments/36ixl0/converting_a_vector_of_bits_to_an_integer/crehkpw/
     slice
@@ -1,34 +1,473 @@
-use crate::{to_int, Cid};
+use crate::{
+    cache::NodeCache,
+    car::CarReader,
+    dag_cbor,
+    selector::Selector,
+    source::{BlockSource, IpfsSource},
+    to_int, Cid, CidDecoder, Value,
+};
+use anyhow::{anyhow, Result};
 use async_recursion::async_recursion;
 use bitvec::prelude::*;
-use futures::TryStreamExt;
-use ipfs_api_backend_hyper::{IpfsApi, IpfsClient};
+use futures::future::{try_join_all, BoxFuture, FutureExt, Shared};
 use minicbor::Decode;
 use multihash::{Code, MultihashDigest};
-use std::ops::Deref;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use static_assertions::assert_impl_all;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Cursor,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Semaphore;
+
+/// Default number of node fetches `get_many` keeps in flight at once
+const DEFAULT_CONCURRENCY: usize = 16;
 
-#[derive(Debug)]
 pub struct RootMapBlock {
     root: MapBlock,
     hash_alg: Code,
     width: usize,
+    source: Arc<dyn BlockSource>,
+    cache: Option<Arc<NodeCache>>,
+    concurrency: usize,
+    prefetch: Option<Arc<Prefetch>>,
+    cids: Option<CidDecoder>,
+}
+
+impl fmt::Debug for RootMapBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RootMapBlock")
+            .field("root", &self.root)
+            .field("hash_alg", &self.hash_alg)
+            .field("width", &self.width)
+            .field("cache", &self.cache)
+            .field("concurrency", &self.concurrency)
+            .field("prefetch", &self.prefetch.is_some())
+            .field("cids", &self.cids)
+            .finish_non_exhaustive()
+    }
+}
+
+type NodeFetch = Shared<BoxFuture<'static, Option<Arc<MapBlock>>>>;
+
+/// Speculative fetches of the children of visited nodes
+struct Prefetch {
+    permits: Arc<Semaphore>,
+    /// Most children started per visited node
+    fanout: usize,
+    in_flight: Mutex<HashMap<Cid, NodeFetch>>,
+}
+
+/// A record stored in a HAMT along with the CID it was fetched by
+#[derive(Debug)]
+pub struct Record {
+    pub cid: Cid,
+    pub block: Vec<u8>,
+}
+
+impl Record {
+    pub fn json(&self) -> Result<JsonValue> {
+        Ok(Value::from_dag_cbor(&self.block)?.0)
+    }
+
+    /// The record as a typed value, for records written with `dag_cbor::to_block`
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(dag_cbor::from_block(&self.block)?)
+    }
+
+    /// The record in CBOR diagnostic notation
+    pub fn diagnostic(&self) -> String {
+        minicbor::display(&self.block).to_string()
+    }
 }
 
+assert_impl_all!(RootMapBlock: Send, Sync);
+
+type Query<'a> = (usize, &'a [u8], &'a BitSlice<Msb0, u8>);
+
 impl RootMapBlock {
-    pub async fn get_key(&self, key: &[u8]) -> Option<Cid> {
+    /// Look up `key`, returning `None` if it is not in the tree and an error if a node on
+    /// its path can not be fetched or decoded.
+    pub async fn get_key(&self, key: &[u8]) -> Result<Option<Cid>> {
+        let multihash = self.hash_alg.digest(key);
+        let digest = BitVec::<Msb0, _>::from_slice(multihash.digest())?;
+
+        // Walk down iteratively rather than recursively so the returned future is Send
+        let mut node: Option<Arc<MapBlock>> = None;
+        let mut depth = 0;
+
+        loop {
+            let current = node.as_deref().unwrap_or(&self.root);
+            self.speculate(current);
+            match current.child(&digest, depth, self.width) {
+                Some(Element::Node(cid)) => {
+                    let next = self.node(cid).await?;
+                    node = Some(next);
+                    depth += 1;
+                }
+                Some(Element::Bucket(b)) => return Ok(find_in_bucket(b, key)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Look up `key` and fetch the record block its value links to.
+    pub async fn get_record(&self, key: &[u8]) -> Result<Option<Record>> {
+        match self.get_key(key).await? {
+            Some(cid) => {
+                if let Some(cids) = &self.cids {
+                    cids.check(&cid)
+                        .map_err(|e| anyhow!("Record link {}: {}", cid, e))?;
+                }
+                let block = self.source.get(&cid).await?;
+                Ok(Some(Record { cid, block }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Look up every key in `keys`, returning the results in the same order.
+    ///
+    /// Keys are grouped by digest prefix so each node on their combined paths is
+    /// fetched only once, and independent subtrees are fetched concurrently. Fails if any
+    /// node on the combined paths can not be fetched or decoded.
+    pub async fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Cid>>> {
+        let digests = keys
+            .iter()
+            .map(|key| {
+                let multihash = self.hash_alg.digest(key.as_ref());
+                BitVec::<Msb0, _>::from_slice(multihash.digest())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let queries: Vec<Query> = keys
+            .iter()
+            .zip(digests.iter())
+            .enumerate()
+            .map(|(i, (key, digest))| (i, key.as_ref(), digest.as_bitslice()))
+            .collect();
+
+        let fetches = Semaphore::new(self.concurrency);
+        let found = self.get_many_in(&self.root, queries, 0, &fetches).await?;
+
+        let mut results = vec![None; keys.len()];
+        for (i, cid) in found {
+            results[i] = cid;
+        }
+        Ok(results)
+    }
+
+    /// Build a selector for the blocks a lookup of `key` visits, so the lookup can be sent
+    /// as a single request to a graphsync or gateway endpoint.
+    ///
+    /// A child's position in a node's data depends on that node's bitmap, so the path can
+    /// only be selected exactly through the root and nodes already in the cache. Below the
+    /// first node that is not cached, the selector recurses into every child, down to the
+    /// deepest level the digest allows. That covers the whole path in one response, along
+    /// with the rest of the subtree and the records of the buckets in it, so a warm cache
+    /// keeps responses small.
+    pub fn key_selector(&self, key: &[u8]) -> Result<Selector> {
+        let multihash = self.hash_alg.digest(key);
+        let digest = BitVec::<Msb0, _>::from_slice(multihash.digest())?;
+
+        let mut positions = vec![];
+        let mut node: Option<Arc<MapBlock>> = None;
+        let mut depth = 0;
+
+        let mut selector = loop {
+            let current = node.as_deref().unwrap_or(&self.root);
+            let (position, element) = match current.child_position(&digest, depth, self.width) {
+                Some(child) => child,
+                None => break Selector::Matcher,
+            };
+            positions.push(position);
+
+            match element {
+                Element::Bucket(_) => break Selector::Matcher,
+                Element::Node(cid) => match self.cache.as_ref().and_then(|c| c.peek(cid)) {
+                    Some(next) => {
+                        node = Some(next);
+                        depth += 1;
+                    }
+                    None => {
+                        // Levels below the child that the digest still has bits for
+                        let levels = (digest.len() / self.width).saturating_sub(depth + 2);
+                        break Selector::ExploreRecursive {
+                            depth: levels as u64,
+                            sequence: Box::new(Selector::ExploreIndex(
+                                1,
+                                Box::new(Selector::ExploreAll(Box::new(
+                                    Selector::ExploreRecursiveEdge,
+                                ))),
+                            )),
+                        };
+                    }
+                },
+            }
+        };
+
+        // Every node is an array of its bitmap and its data
+        for position in positions.into_iter().rev() {
+            selector = Selector::ExploreIndex(
+                1,
+                Box::new(Selector::ExploreIndex(position, Box::new(selector))),
+            );
+        }
+
+        Ok(Selector::ExploreField(
+            "hamt".to_string(),
+            Box::new(selector),
+        ))
+    }
+
+    /// Look up `key` in the blocks of a CAR response to `key_selector`.
+    ///
+    /// Every block is checked against its CID, and the path is walked through the blocks
+    /// of the CAR alone. The root block is trusted, as it is the one this `RootMapBlock`
+    /// was decoded from. An error is returned if the response is missing a block the
+    /// lookup needs, so absence is only reported when it is proven. With a cache, the
+    /// nodes on the path are added to it, so later selectors through them are exact.
+    pub fn verify_car(&self, key: &[u8], car: &[u8]) -> Result<Option<Cid>> {
+        let mut reader = CarReader::new(Cursor::new(car))?.verify(true);
+        let mut blocks = HashMap::new();
+        for block in reader.blocks()? {
+            let (cid, block) = block?;
+            blocks.insert(Cid(cid), block);
+        }
+
         let multihash = self.hash_alg.digest(key);
-        let digest = bitvec::prelude::BitVec::<Msb0, _>::from_slice(multihash.digest()).ok()?;
+        let digest = BitVec::<Msb0, _>::from_slice(multihash.digest())?;
+
+        let mut node: Option<Arc<MapBlock>> = None;
+        let mut depth = 0;
+
+        loop {
+            let current = node.as_deref().unwrap_or(&self.root);
+            match current.child(&digest, depth, self.width) {
+                Some(Element::Node(cid)) => {
+                    let block = blocks
+                        .get(cid)
+                        .ok_or_else(|| anyhow!("CAR is missing block {}", cid))?;
+                    let next = Arc::new(minicbor::decode::<MapBlock>(block)?);
+                    if let Some(cache) = &self.cache {
+                        cache.insert(cid.clone(), Arc::clone(&next), block.len());
+                    }
+                    node = Some(next);
+                    depth += 1;
+                }
+                Some(Element::Bucket(b)) => return Ok(find_in_bucket(b, key)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Fetch every child of the root into the cache, so no lookup has to wait on the first level.
+    pub async fn warm_cache(&self) -> Result<()> {
+        let fetches = Semaphore::new(self.concurrency);
+
+        try_join_all(self.root.children().map(|cid| async {
+            let _permit = fetches.acquire().await;
+            self.node(cid).await.map(|_| ())
+        }))
+        .await?;
+
+        Ok(())
+    }
+
+    /// Fetch nodes from `source` instead of the local IPFS daemon.
+    pub fn with_source(mut self, source: Arc<dyn BlockSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Serve interior nodes from `cache` instead of fetching them on every lookup.
+    pub fn with_cache(mut self, cache: Arc<NodeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&Arc<NodeCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Limit the number of node fetches `get_many` runs concurrently.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
 
-        self.root.get_key(key, &digest, 0, self.width).await
+    /// Speculatively fetch up to `fanout` uncached children of every node a lookup visits,
+    /// with up to `concurrency` fetches in flight across all lookups.
+    ///
+    /// A node can have `2^bitWidth` children, so the fan-out bounds the extra load on the
+    /// block source. Lookups that need a node being prefetched wait on that fetch instead
+    /// of starting another. Prefetched nodes are kept in the node cache, so this has no
+    /// effect without `with_cache`. Background fetches are spawned on the current tokio
+    /// runtime, and skipped when there is none.
+    pub fn with_prefetch(mut self, concurrency: usize, fanout: usize) -> Self {
+        self.prefetch = Some(Arc::new(Prefetch {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            fanout,
+            in_flight: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// Check the link to each record `get_record` fetches, such as rejecting CIDv0 links
+    /// with `CidMode::Strict`.
+    pub fn with_cids(mut self, cids: CidDecoder) -> Self {
+        self.cids = Some(cids);
+        self
+    }
+
+    async fn node(&self, cid: &Cid) -> Result<Arc<MapBlock>> {
+        let cache = self.cache.as_deref();
+        if let Some(node) = cache.and_then(|c| c.get(cid)) {
+            return Ok(node);
+        }
+
+        let in_flight = self
+            .prefetch
+            .as_ref()
+            .and_then(|p| p.in_flight.lock().unwrap().get(cid).cloned());
+        if let Some(fetch) = in_flight {
+            if let Some(node) = fetch.await {
+                return Ok(node);
+            }
+        }
+
+        load_node(&*self.source, cache, cid).await
+    }
+
+    fn speculate(&self, node: &MapBlock) {
+        let (prefetch, cache) = match (&self.prefetch, &self.cache) {
+            (Some(prefetch), Some(cache)) => (prefetch, cache),
+            _ => return,
+        };
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+
+        let children: Vec<&Cid> = node
+            .children()
+            .filter(|child| !cache.contains(child))
+            .take(prefetch.fanout)
+            .collect();
+
+        // Register the fetches under the lock, and spawn them once it is released
+        let mut fetches = vec![];
+        {
+            let mut in_flight = prefetch.in_flight.lock().unwrap();
+            for child in children {
+                if in_flight.contains_key(child) {
+                    continue;
+                }
+                let permit = match Arc::clone(&prefetch.permits).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+
+                let source = Arc::clone(&self.source);
+                let cache = Arc::clone(cache);
+                let prefetch = Arc::clone(prefetch);
+                let cid = child.clone();
+
+                let fetch = async move {
+                    let node = load_node(&*source, Some(&cache), &cid).await.ok();
+                    prefetch.in_flight.lock().unwrap().remove(&cid);
+                    drop(permit);
+                    node
+                }
+                .boxed()
+                .shared();
+
+                in_flight.insert(child.clone(), fetch.clone());
+                fetches.push(fetch);
+            }
+        }
+
+        for fetch in fetches {
+            runtime.spawn(fetch);
+        }
+    }
+
+    #[async_recursion]
+    async fn get_many_in<'a>(
+        &'a self,
+        node: &'a MapBlock,
+        queries: Vec<Query<'a>>,
+        depth: usize,
+        fetches: &'a Semaphore,
+    ) -> Result<Vec<(usize, Option<Cid>)>> {
+        self.speculate(node);
+
+        let width = self.width;
+        let offset = depth * width;
+
+        let mut groups: BTreeMap<usize, Vec<Query>> = BTreeMap::new();
+        let mut results = vec![];
+
+        for query in queries {
+            match query.2.get(offset..(offset + width)) {
+                Some(index) => groups.entry(to_int(index)).or_default().push(query),
+                None => results.push((query.0, None)),
+            }
+        }
+
+        let mut subtrees = vec![];
+
+        for (index, group) in groups {
+            match node.elements.get(index).and_then(Option::as_ref) {
+                Some(Element::Node(n)) => subtrees.push(async move {
+                    let child = {
+                        let _permit = fetches.acquire().await;
+                        self.node(n).await?
+                    };
+                    self.get_many_in(&child, group, depth + 1, fetches).await
+                }),
+                Some(Element::Bucket(b)) => {
+                    results.extend(group.into_iter().map(|q| (q.0, find_in_bucket(b, q.1))))
+                }
+                None => results.extend(group.into_iter().map(|q| (q.0, None))),
+            }
+        }
+
+        for found in try_join_all(subtrees).await? {
+            results.extend(found);
+        }
+        Ok(results)
     }
 }
 
+async fn load_node(
+    source: &dyn BlockSource,
+    cache: Option<&NodeCache>,
+    cid: &Cid,
+) -> Result<Arc<MapBlock>> {
+    let block = source.get(cid).await?;
+
+    let node = Arc::new(minicbor::decode::<MapBlock>(&block)?);
+    if let Some(cache) = cache {
+        cache.insert(cid.clone(), Arc::clone(&node), block.len());
+    }
+
+    Ok(node)
+}
+
 impl<'b> Decode<'b> for RootMapBlock {
     fn decode(d: &mut minicbor::Decoder<'b>) -> Result<Self, minicbor::decode::Error> {
-        let length = d.map()?.unwrap();
+        let length = d.map()?.ok_or(minicbor::decode::Error::Message(
+            "Root map must have a definite length",
+        ))?;
 
         let mut root: Option<MapBlock> = None;
         let mut hash_alg: Option<u64> = None;
+        let mut bit_width: Option<u64> = None;
 
         for _ in 0..length {
             let map_key = d.str()?;
@@ -36,6 +475,8 @@ impl<'b> Decode<'b> for RootMapBlock {
                 root = d.decode()?;
             } else if map_key == "hashAlg" {
                 hash_alg = d.decode()?;
+            } else if map_key == "bitWidth" {
+                bit_width = d.decode()?;
             } else {
                 d.skip()?;
             }
@@ -44,87 +485,138 @@ impl<'b> Decode<'b> for RootMapBlock {
         let root = root.ok_or(minicbor::decode::Error::EndOfInput)?;
         let hash_alg = hash_alg.ok_or(minicbor::decode::Error::EndOfInput)?;
 
-        let width = log_2(root.elements.len()) as usize;
+        let width = match bit_width {
+            Some(width) => {
+                let width = usize::try_from(width)
+                    .ok()
+                    .filter(|w| (1..=MAX_WIDTH).contains(w))
+                    .ok_or(minicbor::decode::Error::Message("Invalid bitWidth"))?;
+                if root.elements.len() != bitmap_len(width) {
+                    return Err(minicbor::decode::Error::Message(
+                        "Root bitmap length does not match bitWidth",
+                    ));
+                }
+                width
+            }
+            // Roots written before bitWidth was stored, only correct for widths of 3 or more
+            None => log_2(root.elements.len())
+                .ok_or(minicbor::decode::Error::Message("Root bitmap is empty"))?
+                as usize,
+        };
 
         Ok(RootMapBlock {
             root,
             width,
+            source: Arc::new(IpfsSource::default()),
+            cache: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            prefetch: None,
+            cids: None,
             hash_alg: Code::try_from(hash_alg)
                 .map_err(|_| minicbor::decode::Error::Message("Invalid hash_alg"))?,
         })
     }
 }
 
+/// Widest node supported when decoding, anything larger would need a multi megabyte bitmap
+const MAX_WIDTH: usize = 16;
+
+/// Number of bits in the bitmap of a node with `2^width` children, padded to whole bytes
+fn bitmap_len(width: usize) -> usize {
+    (1usize << width).max(8)
+}
+
 const fn num_bits<T>() -> usize {
     std::mem::size_of::<T>() * 8
 }
 
-fn log_2(x: usize) -> u32 {
-    assert!(x > 0);
-    num_bits::<usize>() as u32 - x.leading_zeros() - 1
+fn log_2(x: usize) -> Option<u32> {
+    if x == 0 {
+        return None;
+    }
+    Some(num_bits::<usize>() as u32 - x.leading_zeros() - 1)
 }
 
 #[derive(Debug)]
-struct MapBlock {
+pub(crate) struct MapBlock {
     elements: Vec<Option<Element>>,
 }
 
 impl MapBlock {
-    async fn get(hash: &Cid) -> Result<Self, minicbor::decode::Error> {
-        let client = IpfsClient::default();
+    /// CIDs of the child nodes, in the order they are stored in the node's data
+    fn children(&self) -> impl Iterator<Item = &Cid> {
+        self.elements.iter().flatten().filter_map(|e| match e {
+            Element::Node(cid) => Some(cid),
+            Element::Bucket(_) => None,
+        })
+    }
 
-        let block = client
-            .block_get(&hash.to_string())
-            .map_ok(|chunk| chunk.to_vec())
-            .try_concat()
-            .await
-            .map_err(|_| minicbor::decode::Error::EndOfInput)?;
+    fn child(&self, digest: &BitSlice<Msb0, u8>, depth: usize, width: usize) -> Option<&Element> {
+        let offset = depth * width;
+        // A malformed tree can be deeper than the digest has bits for
+        let index = to_int(digest.get(offset..(offset + width))?);
 
-        minicbor::decode(&block)
+        self.elements.get(index).and_then(Option::as_ref)
     }
 
-    #[async_recursion(?Send)]
-    async fn get_key(
+    /// Like `child`, along with the position of the child in the node's data
+    fn child_position(
         &self,
-        key: &[u8],
         digest: &BitSlice<Msb0, u8>,
         depth: usize,
         width: usize,
-    ) -> Option<Cid> {
+    ) -> Option<(usize, &Element)> {
         let offset = depth * width;
-        let index = &digest[offset..(offset + width)];
-        let index = to_int(index);
-
-        match self.elements.get(index).map(Option::as_ref).flatten() {
-            Some(e) => match e {
-                Element::Node(n) => {
-                    let n = MapBlock::get(n).await.ok()?;
-                    let result = n.get_key(key, digest, depth + 1, width).await;
-                    result
-                }
-                Element::Bucket(b) => match b.binary_search_by(|v| key.cmp(&v.0)) {
-                    Ok(i) => Some(b[i].1.clone()),
-                    Err(_) => None,
-                },
-            },
-            None => None,
-        }
+        let index = to_int(digest.get(offset..(offset + width))?);
+
+        let element = self.elements.get(index)?.as_ref()?;
+        let position = self.elements[..index].iter().flatten().count();
+        Some((position, element))
+    }
+}
+
+/// CIDs of the child nodes of a tree block, which is the root block if `root` is set
+pub(crate) fn child_nodes(block: &[u8], root: bool) -> Result<Vec<Cid>> {
+    let node = match root {
+        true => minicbor::decode::<RootMapBlock>(block)?.root,
+        false => minicbor::decode::<MapBlock>(block)?,
+    };
+    Ok(node.children().cloned().collect())
+}
+
+fn find_in_bucket(bucket: &[BucketEntry], key: &[u8]) -> Option<Cid> {
+    match bucket.binary_search_by(|v| key.cmp(&v.0)) {
+        Ok(i) => Some(bucket[i].1.clone()),
+        Err(_) => None,
     }
 }
 
 impl<'b> Decode<'b> for MapBlock {
     fn decode(d: &mut minicbor::Decoder<'b>) -> Result<Self, minicbor::decode::Error> {
-        d.array()?;
+        if d.array()? != Some(2) {
+            return Err(minicbor::decode::Error::Message(
+                "Node must be an array of a bitmap and data",
+            ));
+        }
+
         let map = d.bytes()?;
-        let map: &BitSlice<Msb0, u8> = BitSlice::from_slice(map).unwrap();
+        let map: &BitSlice<Msb0, u8> = BitSlice::from_slice(map)
+            .map_err(|_| minicbor::decode::Error::Message("Node bitmap is too long"))?;
 
-        let mut elements: Vec<Option<Element>> = vec![];
-        let mut cids = d.array_iter::<Element>().unwrap();
+        let length = d.array()?.ok_or(minicbor::decode::Error::Message(
+            "Node data must have a definite length",
+        ))?;
+        if length != map.count_ones() as u64 {
+            return Err(minicbor::decode::Error::Message(
+                "Node bitmap does not match the length of its data",
+            ));
+        }
 
+        let mut elements: Vec<Option<Element>> = Vec::with_capacity(map.len());
         for value in map {
             let value = value.deref();
             match value {
-                true => elements.push(Some(cids.next().unwrap().unwrap())),
+                true => elements.push(Some(d.decode()?)),
                 false => elements.push(None),
             }
         }
@@ -133,6 +625,23 @@ impl<'b> Decode<'b> for MapBlock {
     }
 }
 
+/// Decoding entry points for the cargo-fuzz targets in `fuzz/`
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    pub fn decode_root_map_block(data: &[u8]) {
+        let _ = minicbor::decode::<super::RootMapBlock>(data);
+    }
+
+    pub fn decode_map_block(data: &[u8]) {
+        let _ = minicbor::decode::<super::MapBlock>(data);
+    }
+
+    pub fn decode_element(data: &[u8]) {
+        let _ = minicbor::decode::<super::Element>(data);
+    }
+}
+
 type BucketEntry = (Vec<u8>, Cid);
 
 #[derive(Debug)]
@@ -148,9 +657,15 @@ impl<'b> Decode<'b> for Element {
             Err(_) => {
                 let mut entries: Vec<BucketEntry> = vec![];
 
-                let length = d.array()?.unwrap();
+                let length = d.array()?.ok_or(minicbor::decode::Error::Message(
+                    "Bucket must have a definite length",
+                ))?;
                 for _ in 0..length {
-                    d.array()?;
+                    if d.array()? != Some(2) {
+                        return Err(minicbor::decode::Error::Message(
+                            "Bucket entry must be an array of a key and value",
+                        ));
+                    }
                     let entry: BucketEntry = (d.bytes()?.to_vec(), d.decode()?);
                     entries.push(entry);
                 }
@@ -160,3 +675,569 @@ impl<'b> Decode<'b> for Element {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::CacheLimit, car::Car, source::Delayed};
+    use cid::Cid as ExtCid;
+    use libipld::{cbor::DagCborCodec, codec::Codec, Ipld};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    fn value(key: &[u8]) -> Cid {
+        Cid(ExtCid::new_v1(0x55, Code::Sha2_256.digest(key)))
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key-{}", i).into_bytes()
+    }
+
+    /// Write the node holding `entries` at `depth` to `tree`, splitting buckets with more
+    /// than `bucket_size` entries into child nodes
+    fn write_node(
+        tree: &sled::Tree,
+        mut entries: Vec<(BitVec<Msb0, u8>, Vec<u8>)>,
+        depth: usize,
+        width: usize,
+        bucket_size: usize,
+    ) -> Vec<u8> {
+        let mut slots: BTreeMap<usize, Vec<_>> = BTreeMap::new();
+        // Buckets hold their entries in descending key order
+        entries.sort_by(|a, b| b.1.cmp(&a.1));
+        for entry in entries {
+            let index = to_int(&entry.0[depth * width..(depth + 1) * width]);
+            slots.entry(index).or_default().push(entry);
+        }
+
+        let mut bitmap = bitvec![Msb0, u8; 0; 1 << width];
+        let mut data = vec![];
+        for (index, slot) in slots {
+            bitmap.set(index, true);
+            if slot.len() > bucket_size {
+                let block = write_node(tree, slot, depth + 1, width, bucket_size);
+                let cid = Cid(ExtCid::new_v1(0x71, Code::Sha2_256.digest(&block)));
+                tree.insert(cid.0.to_bytes(), block).unwrap();
+                data.push(Ipld::Link(cid.0));
+            } else {
+                let bucket = slot.into_iter().map(|(_, key)| {
+                    Ipld::List(vec![Ipld::Bytes(key.clone()), Ipld::Link(value(&key).0)])
+                });
+                data.push(Ipld::List(bucket.collect()));
+            }
+        }
+
+        let node = Ipld::List(vec![Ipld::Bytes(bitmap.into_vec()), Ipld::List(data)]);
+        DagCborCodec.encode(&node).unwrap()
+    }
+
+    fn root_block(root: Vec<u8>, width: usize, bucket_size: usize, bit_width: bool) -> Vec<u8> {
+        let mut e = minicbor::Encoder::new(vec![]);
+        e.map(if bit_width { 4 } else { 3 }).unwrap();
+        e.str("hashAlg")
+            .unwrap()
+            .u64(Code::Sha2_256.into())
+            .unwrap();
+        if bit_width {
+            e.str("bitWidth").unwrap().u64(width as u64).unwrap();
+        }
+        e.str("bucketSize")
+            .unwrap()
+            .u64(bucket_size as u64)
+            .unwrap();
+        e.str("hamt").unwrap();
+        let mut block = e.into_inner();
+        block.extend(root);
+        block
+    }
+
+    /// Build a tree of `count` keys into a temporary db, returning the db and root block
+    fn build_block(count: usize, width: usize, bucket_size: usize) -> (sled::Tree, Vec<u8>) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+
+        let entries = (0..count)
+            .map(|i| {
+                let digest = Code::Sha2_256.digest(&key(i));
+                (BitVec::from_slice(digest.digest()).unwrap(), key(i))
+            })
+            .collect();
+        let root = write_node(&tree, entries, 0, width, bucket_size);
+        (tree, root_block(root, width, bucket_size, true))
+    }
+
+    fn build(count: usize, width: usize, bucket_size: usize) -> (sled::Tree, RootMapBlock) {
+        let (tree, block) = build_block(count, width, bucket_size);
+        (tree, minicbor::decode::<RootMapBlock>(&block).unwrap())
+    }
+
+    /// Look up `key` by decoding the blocks in `tree` directly
+    fn lookup(tree: &sled::Tree, root: &RootMapBlock, key: &[u8]) -> Option<Cid> {
+        let digest = Code::Sha2_256.digest(key);
+        let digest = BitVec::<Msb0, _>::from_slice(digest.digest()).unwrap();
+
+        let mut node = None;
+        for depth in 0.. {
+            let current = node.as_ref().unwrap_or(&root.root);
+            match current.child(&digest, depth, root.width)? {
+                Element::Node(cid) => {
+                    let block = tree.get(cid.0.to_bytes()).unwrap().unwrap();
+                    node = Some(minicbor::decode::<MapBlock>(&block).unwrap());
+                }
+                Element::Bucket(b) => return find_in_bucket(b, key),
+            }
+        }
+        unreachable!()
+    }
+
+    /// A cache holding every node in `tree`, so lookups never fetch
+    fn cached(tree: &sled::Tree) -> Arc<NodeCache> {
+        let cache = Arc::new(NodeCache::new(CacheLimit::Entries(tree.len() + 1)));
+        for entry in tree.iter() {
+            let (cid, block) = entry.unwrap();
+            let cid = Cid(ExtCid::try_from(&cid[..]).unwrap());
+            let node = minicbor::decode(&block).unwrap();
+            cache.insert(cid, Arc::new(node), block.len());
+        }
+        cache
+    }
+
+    /// Counts the fetches of each block and the most fetches in flight at once
+    struct Counting<S> {
+        source: S,
+        fetches: Mutex<HashMap<Cid, usize>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl<S> Counting<S> {
+        fn new(source: S) -> Self {
+            Counting {
+                source,
+                fetches: Mutex::new(HashMap::new()),
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            }
+        }
+
+        fn total(&self) -> usize {
+            self.fetches.lock().unwrap().values().sum()
+        }
+    }
+
+    impl<S: BlockSource> BlockSource for Counting<S> {
+        fn get<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Vec<u8>>> {
+            Box::pin(async move {
+                *self.fetches.lock().unwrap().entry(cid.clone()).or_default() += 1;
+                let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                let block = self.source.get(cid).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                block
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn get_many_returns_results_in_input_order() {
+        let (tree, root) = build(200, 3, 2);
+        let root = root.with_cache(cached(&tree));
+
+        let keys = vec![key(5), b"missing".to_vec(), key(199), key(5), key(0)];
+        let found = root.get_many(&keys).await.unwrap();
+
+        assert_eq!(
+            found,
+            vec![
+                Some(value(&key(5))),
+                None,
+                Some(value(&key(199))),
+                Some(value(&key(5))),
+                Some(value(&key(0))),
+            ]
+        );
+        for (key, found) in keys.iter().zip(found) {
+            assert_eq!(root.get_key(key).await.unwrap(), found);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_many_fetches_each_node_once_within_concurrency() {
+        let (tree, root) = build(500, 3, 1);
+        let source = Arc::new(Counting::new(tree));
+        let root = root.with_source(source.clone()).with_concurrency(3);
+
+        let mut keys: Vec<Vec<u8>> = (0..500).map(key).collect();
+        keys.extend((0..100).map(key));
+        let found = root.get_many(&keys).await.unwrap();
+
+        assert!(found.iter().all(Option::is_some));
+        assert!(source.fetches.lock().unwrap().values().all(|&n| n == 1));
+        assert!(source.total() > 8);
+        assert_eq!(source.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn lookups_can_be_spawned() {
+        let (tree, root) = build(50, 3, 1);
+        let root = Arc::new(root.with_cache(cached(&tree)));
+
+        let one = tokio::spawn({
+            let root = Arc::clone(&root);
+            async move { root.get_key(&key(7)).await }
+        });
+        let many = tokio::spawn({
+            let root = Arc::clone(&root);
+            async move { root.get_many(&[key(1), key(2)]).await }
+        });
+
+        assert_eq!(one.await.unwrap().unwrap(), Some(value(&key(7))));
+        assert_eq!(
+            many.await.unwrap().unwrap(),
+            vec![Some(value(&key(1))), Some(value(&key(2)))]
+        );
+    }
+
+    /// Replace the first child of the root with `block`, returning a key below it
+    fn corrupt_child(tree: &sled::Tree, root: &RootMapBlock, block: &[u8]) -> Vec<u8> {
+        let child = root.root.children().next().unwrap().clone();
+        tree.insert(child.0.to_bytes(), block).unwrap();
+
+        (0..)
+            .map(key)
+            .find(|key| {
+                let digest = Code::Sha2_256.digest(key);
+                let digest = BitVec::<Msb0, _>::from_slice(digest.digest()).unwrap();
+                matches!(root.root.child(&digest, 0, root.width), Some(Element::Node(cid)) if *cid == child)
+            })
+            .unwrap()
+    }
+
+    /// Blocks that are not valid nodes
+    const INVALID_NODES: [&[u8]; 4] = [
+        // Truncated node
+        &[0x82, 0x41],
+        // Array of three
+        &[0x83, 0x40, 0x80, 0x80],
+        // Two bits set in the bitmap and one element in the data
+        &[0x82, 0x41, 0xc0, 0x81, 0x80],
+        // Not CBOR at all
+        b"not a node",
+    ];
+
+    #[test]
+    fn invalid_nodes_do_not_decode() {
+        for block in INVALID_NODES {
+            assert!(minicbor::decode::<MapBlock>(block).is_err());
+        }
+        // A bucket entry that is not a pair
+        assert!(minicbor::decode::<MapBlock>(&[0x82, 0x41, 0x80, 0x81, 0x81, 0x81, 0x40]).is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_nodes_are_errors() {
+        for block in INVALID_NODES {
+            let (tree, root) = build(100, 3, 1);
+            let below = corrupt_child(&tree, &root, block);
+            let root = root.with_source(Arc::new(tree));
+
+            assert!(root.get_key(&below).await.is_err());
+            assert!(root.get_many(&[key(0), below]).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_nodes_are_errors() {
+        let (tree, root) = build(100, 3, 1);
+        let key = corrupt_child(&tree, &root, b"");
+        tree.remove(root.root.children().next().unwrap().0.to_bytes())
+            .unwrap();
+        let root = root.with_source(Arc::new(tree));
+
+        assert!(root.get_key(&key).await.is_err());
+        assert!(root.get_record(&key).await.is_err());
+    }
+
+    #[test]
+    fn root_bitmap_must_match_bit_width() {
+        let (_, block) = build_block(20, 3, 1);
+        // An 8 bit bitmap can not have a bitWidth of 4
+        let mut block = block;
+        let width = block.windows(9).position(|w| w == b"\x68bitWidth").unwrap() + 9;
+        block[width] = 4;
+        assert!(minicbor::decode::<RootMapBlock>(&block).is_err());
+
+        block[width] = 0;
+        assert!(minicbor::decode::<RootMapBlock>(&block).is_err());
+        block[width] = 17;
+        assert!(minicbor::decode::<RootMapBlock>(&block).is_err());
+    }
+
+    #[test]
+    fn root_without_bit_width_uses_bitmap_length() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let entries = (0..300)
+            .map(|i| {
+                let digest = Code::Sha2_256.digest(&key(i));
+                (BitVec::from_slice(digest.digest()).unwrap(), key(i))
+            })
+            .collect();
+        let node = write_node(&tree, entries, 0, 4, 2);
+
+        let root = minicbor::decode::<RootMapBlock>(&root_block(node, 4, 2, false)).unwrap();
+        assert_eq!(root.width, 4);
+        for i in 0..300 {
+            assert_eq!(lookup(&tree, &root, &key(i)), Some(value(&key(i))));
+        }
+    }
+
+    #[test]
+    fn built_trees_decode_at_every_width() {
+        for width in [1, 2, 8] {
+            let mut map = crate::IpldHashMap::new(width, 3);
+            for i in 0..300 {
+                map.set(key(i).into_boxed_slice(), value(&key(i))).unwrap();
+            }
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            let tree = db.open_tree("tree").unwrap();
+            let cid = map.collapse(&tree);
+
+            // The same layout as writing each node directly
+            let block = tree.get(cid.0.to_bytes()).unwrap().unwrap();
+            let (expected, expected_block) = build_block(300, width, 3);
+            assert_eq!(&block[..], &expected_block[..]);
+            assert_eq!(tree.len(), expected.len() + 1);
+
+            let root = minicbor::decode::<RootMapBlock>(&block).unwrap();
+            assert_eq!(root.width, width);
+            for i in 0..300 {
+                assert_eq!(lookup(&tree, &root, &key(i)), Some(value(&key(i))));
+            }
+            assert_eq!(lookup(&tree, &root, b"missing"), None);
+        }
+    }
+
+    #[test]
+    fn records_print_as_json_and_diagnostic() {
+        // {"id": 7, "name": "seven"}
+        let block = b"\xa2bid\x07dnameeseven".to_vec();
+        let record = Record {
+            cid: value(&block),
+            block,
+        };
+
+        assert_eq!(
+            record.json().unwrap(),
+            serde_json::json!({"id": 7, "name": "seven"})
+        );
+        assert_eq!(record.diagnostic(), r#"{"id": 7, "name": "seven"}"#);
+    }
+
+    #[tokio::test]
+    async fn get_record_fetches_the_linked_block() {
+        let (tree, root) = build(50, 3, 1);
+        // Each value links to a raw block holding the key
+        tree.insert(value(&key(4)).0.to_bytes(), key(4)).unwrap();
+        let root = root.with_source(Arc::new(tree));
+
+        let record = root.get_record(&key(4)).await.unwrap().unwrap();
+        assert_eq!(record.cid, value(&key(4)));
+        assert_eq!(record.block, key(4));
+
+        assert!(root.get_record(b"missing").await.unwrap().is_none());
+        // In the tree, but the record block is not available
+        assert!(root.get_record(&key(5)).await.is_err());
+    }
+
+    /// Run one lookup with prefetching, returning how many of the root's children ended up
+    /// in the cache and the source the nodes were fetched from
+    async fn prefetch_lookup(
+        concurrency: usize,
+        fanout: usize,
+    ) -> (usize, Arc<Counting<Delayed<sled::Tree>>>) {
+        let (tree, root) = build(500, 3, 1);
+        let source = Arc::new(Counting::new(Delayed::new(tree, Duration::from_millis(20))));
+        let cache = Arc::new(NodeCache::new(CacheLimit::Entries(1000)));
+        let root = root
+            .with_source(source.clone())
+            .with_cache(cache.clone())
+            .with_prefetch(concurrency, fanout);
+
+        assert_eq!(root.get_key(&key(0)).await.unwrap(), Some(value(&key(0))));
+        // Let the background fetches finish
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let cached = root.root.children().filter(|c| cache.contains(c)).count();
+        (cached, source)
+    }
+
+    #[tokio::test]
+    async fn prefetch_fills_the_cache_up_to_the_fanout() {
+        let (cached, source) = prefetch_lookup(16, 3).await;
+
+        // Three prefetched children, and the one on the path if it was not among them
+        assert!((3..=4).contains(&cached));
+        // Lookups wait on a prefetch of the same node instead of fetching it again
+        assert!(source.fetches.lock().unwrap().values().all(|&n| n == 1));
+    }
+
+    #[tokio::test]
+    async fn prefetch_respects_the_concurrency_cap() {
+        let (cached, source) = prefetch_lookup(1, 8).await;
+
+        assert!(cached <= 2);
+        // One prefetch and the lookup's own fetch
+        assert!(source.max_in_flight.load(Ordering::SeqCst) <= 2);
+    }
+
+    /// Walk `selector` over `node` the way a graphsync responder would, collecting the CIDs of
+    /// the blocks it loads. `recursion` is the innermost `ExploreRecursive`.
+    fn select(
+        tree: &sled::Tree,
+        selector: &Selector,
+        node: &Ipld,
+        recursion: Option<(u64, &Selector)>,
+        loaded: &mut Vec<Cid>,
+    ) {
+        let mut visit = |next: &Selector, child: &Ipld, recursion: Option<(u64, &Selector)>| {
+            if let (Selector::ExploreRecursiveEdge, Some((0, _))) = (next, recursion) {
+                return;
+            }
+            match child {
+                Ipld::Link(cid) => {
+                    if let Some(block) = tree.get(cid.to_bytes()).unwrap() {
+                        loaded.push(Cid(*cid));
+                        let child = DagCborCodec.decode(&block).unwrap();
+                        select(tree, next, &child, recursion, loaded);
+                    }
+                }
+                child => select(tree, next, child, recursion, loaded),
+            }
+        };
+
+        match (selector, node) {
+            (Selector::Matcher, _) => {}
+            (Selector::ExploreField(field, next), Ipld::StringMap(map)) => {
+                if let Some(child) = map.get(field) {
+                    visit(next, child, recursion);
+                }
+            }
+            (Selector::ExploreIndex(index, next), Ipld::List(list)) => {
+                if let Some(child) = list.get(*index) {
+                    visit(next, child, recursion);
+                }
+            }
+            (Selector::ExploreAll(next), Ipld::List(list)) => {
+                for child in list {
+                    visit(next, child, recursion);
+                }
+            }
+            (Selector::ExploreAll(next), Ipld::StringMap(map)) => {
+                for child in map.values() {
+                    visit(next, child, recursion);
+                }
+            }
+            (Selector::ExploreRecursive { depth, sequence }, _) => {
+                select(tree, sequence, node, Some((*depth, sequence)), loaded)
+            }
+            (Selector::ExploreRecursiveEdge, _) => {
+                if let Some((depth, sequence)) = recursion {
+                    select(tree, sequence, node, Some((depth - 1, sequence)), loaded);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The CAR a responder would send for `selector`, with every block it loaded
+    fn respond(tree: &sled::Tree, root: &[u8], selector: &Selector) -> (Vec<u8>, Vec<Cid>) {
+        let mut loaded = vec![];
+        select(
+            tree,
+            selector,
+            &DagCborCodec.decode(root).unwrap(),
+            None,
+            &mut loaded,
+        );
+
+        let root_cid = ExtCid::new_v1(0x71, Code::Sha2_256.digest(root));
+        let mut car = Car::new(vec![], vec![root_cid]);
+        car.encode_header().unwrap();
+        car.write_block_cid(&root_cid, root).unwrap();
+        for cid in &loaded {
+            let block = tree.get(cid.0.to_bytes()).unwrap().unwrap();
+            car.write_block_cid(&cid.0, &block).unwrap();
+        }
+        (car.into_inner(), loaded)
+    }
+
+    #[test]
+    fn key_selector_covers_the_path_in_one_request() {
+        let (tree, block) = build_block(2000, 3, 1);
+        let root = minicbor::decode::<RootMapBlock>(&block).unwrap();
+        // Records of the buckets in the selected subtree are sent too
+        for i in 0..2000 {
+            tree.insert(value(&key(i)).0.to_bytes(), key(i)).unwrap();
+        }
+
+        for i in [0, 17, 1999] {
+            let selector = root.key_selector(&key(i)).unwrap();
+            let (car, _) = respond(&tree, &block, &selector);
+            assert_eq!(
+                root.verify_car(&key(i), &car).unwrap(),
+                Some(value(&key(i)))
+            );
+        }
+
+        let selector = root.key_selector(b"missing").unwrap();
+        let (car, _) = respond(&tree, &block, &selector);
+        assert_eq!(root.verify_car(b"missing", &car).unwrap(), None);
+    }
+
+    #[test]
+    fn key_selector_is_exact_through_cached_nodes() {
+        let (tree, block) = build_block(2000, 3, 1);
+        let cache = Arc::new(NodeCache::new(CacheLimit::Entries(1000)));
+        let root = minicbor::decode::<RootMapBlock>(&block)
+            .unwrap()
+            .with_cache(cache.clone());
+
+        let selector = root.key_selector(&key(3)).unwrap();
+        let (car, cold) = respond(&tree, &block, &selector);
+        assert_eq!(
+            root.verify_car(&key(3), &car).unwrap(),
+            Some(value(&key(3)))
+        );
+        assert!(!cache.is_empty());
+
+        // The second request only selects the nodes on the path
+        let selector = root.key_selector(&key(3)).unwrap();
+        let (car, warm) = respond(&tree, &block, &selector);
+        assert!(warm.len() < cold.len());
+        assert_eq!(warm.len(), cache.len());
+        assert_eq!(
+            root.verify_car(&key(3), &car).unwrap(),
+            Some(value(&key(3)))
+        );
+
+        // The cache only narrows the selector, the response is still needed
+        cache.clear();
+        assert_eq!(
+            root.verify_car(&key(3), &car).unwrap(),
+            Some(value(&key(3)))
+        );
+    }
+
+    #[test]
+    fn verify_car_needs_every_block_on_the_path() {
+        let (tree, block) = build_block(2000, 3, 1);
+        let root = minicbor::decode::<RootMapBlock>(&block).unwrap();
+
+        let (car, _) = respond(&tree, &block, &Selector::Matcher);
+        assert!(root.verify_car(&key(3), &car).is_err());
+    }
+}
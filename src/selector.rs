@@ -0,0 +1,123 @@
+use minicbor::{encode, Encode};
+
+/// The subset of IPLD selectors needed to walk a HAMT, encoded with the
+/// short keys from the selector spec.
+///
+/// https://ipld.io/specs/selectors/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// Select the current node
+    Matcher,
+    /// Explore every child of a map or list
+    ExploreAll(Box<Selector>),
+    /// Explore a single field of a map
+    ExploreField(String, Box<Selector>),
+    /// Explore a single index of a list
+    ExploreIndex(usize, Box<Selector>),
+    /// Repeat `sequence` at every `ExploreRecursiveEdge`, at most `depth` times
+    ExploreRecursive { depth: u64, sequence: Box<Selector> },
+    /// Restart the enclosing `ExploreRecursive`
+    ExploreRecursiveEdge,
+}
+
+impl Encode for Selector {
+    fn encode<W: encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), encode::Error<W::Error>> {
+        e.map(1)?;
+        match self {
+            Selector::Matcher => {
+                e.str(".")?.map(0)?;
+            }
+            Selector::ExploreAll(next) => {
+                e.str("a")?.map(1)?.str(">")?.encode(next)?;
+            }
+            Selector::ExploreField(field, next) => {
+                e.str("f")?
+                    .map(1)?
+                    .str("f>")?
+                    .map(1)?
+                    .str(field)?
+                    .encode(next)?;
+            }
+            Selector::ExploreIndex(index, next) => {
+                // dag-cbor orders map keys by length and then bytewise, so ">" comes first
+                e.str("i")?.map(2)?.str(">")?.encode(next)?;
+                e.str("i")?.encode(index)?;
+            }
+            Selector::ExploreRecursive { depth, sequence } => {
+                e.str("R")?
+                    .map(2)?
+                    .str("l")?
+                    .map(1)?
+                    .str("depth")?
+                    .u64(*depth)?;
+                e.str(":>")?.encode(sequence)?;
+            }
+            Selector::ExploreRecursiveEdge => {
+                e.str("@")?.map(0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Selector {
+    /// The selector as a dag-cbor block, ready to send with a graphsync or gateway request
+    pub fn to_dag_cbor(&self) -> Vec<u8> {
+        minicbor::to_vec(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{cbor::DagCborCodec, codec::Codec, ipld, Ipld};
+
+    #[test]
+    fn matcher_is_an_empty_map() {
+        assert_eq!(Selector::Matcher.to_dag_cbor(), b"\xa1\x61.\xa0");
+    }
+
+    #[test]
+    fn encodes_with_keys_in_dag_cbor_order() {
+        let edge = Selector::ExploreAll(Box::new(Selector::ExploreRecursiveEdge));
+        let selector = Selector::ExploreField(
+            "hamt".to_string(),
+            Box::new(Selector::ExploreIndex(
+                1,
+                Box::new(Selector::ExploreRecursive {
+                    depth: 300,
+                    sequence: Box::new(Selector::ExploreIndex(1, Box::new(edge))),
+                }),
+            )),
+        );
+        let block = selector.to_dag_cbor();
+
+        // Shorter keys first, so "l" before ":>" and ">" before "i"
+        let expected: &[u8] = &[
+            &b"\xa1\x61f\xa1\x62f>\xa1\x64hamt"[..],
+            b"\xa1\x61i\xa2\x61>",
+            b"\xa1\x61R\xa2\x61l\xa1\x65depth\x19\x01\x2c\x62:>",
+            b"\xa1\x61i\xa2\x61>\xa1\x61a\xa1\x61>\xa1\x61@\xa0\x61i\x01",
+            b"\x61i\x01",
+        ]
+        .concat();
+        assert_eq!(block, expected);
+
+        let decoded: Ipld = DagCborCodec.decode(&block).unwrap();
+        assert_eq!(
+            decoded,
+            ipld!({
+                "f": {"f>": {"hamt": {"i": {
+                    "i": 1,
+                    ">": {"R": {
+                        "l": {"depth": 300},
+                        ":>": {"i": {"i": 1, ">": {"a": {">": {"@": {}}}}}},
+                    }},
+                }}}},
+            })
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use crate::Cid;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use hyper::client::HttpConnector;
+use ipfs_api_backend_hyper::{request::BlockGet, IpfsClient};
+use ipfs_api_prelude::Backend;
+use sled::Tree;
+
+use std::time::Duration;
+
+/// Anything raw blocks can be fetched from by CID.
+///
+/// The returned futures are boxed so sources can be used as trait objects.
+pub trait BlockSource: Send + Sync {
+    fn get<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Vec<u8>>>;
+}
+
+/// Fetches blocks from the local IPFS daemon.
+pub struct IpfsSource {
+    client: IpfsClient,
+    http: hyper::Client<HttpConnector>,
+}
+
+impl Default for IpfsSource {
+    fn default() -> Self {
+        IpfsSource {
+            client: IpfsClient::default(),
+            http: hyper::Client::new(),
+        }
+    }
+}
+
+impl BlockSource for IpfsSource {
+    /// The request is built by `IpfsClient` so the daemon address is resolved the same
+    /// way, but sent with a plain hyper client because `IpfsApi` futures are not Send.
+    fn get<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let request = self.client.build_base_request(
+                &BlockGet {
+                    hash: &cid.to_string(),
+                },
+                None,
+            )?;
+
+            let response = self.http.request(request).await?;
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Block {} could not be fetched: {}",
+                    cid,
+                    response.status()
+                ));
+            }
+
+            Ok(hyper::body::to_bytes(response.into_body()).await?.to_vec())
+        })
+    }
+}
+
+/// Blocks stored by CID bytes, the layout of the tree db written by `collapse`.
+impl BlockSource for Tree {
+    fn get<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            Tree::get(self, cid.0.to_bytes())?
+                .map(|block| block.to_vec())
+                .ok_or_else(|| anyhow!("Block {} not found", cid))
+        })
+    }
+}
+
+/// Waits before every fetch, standing in for a high latency network with a local source.
+pub struct Delayed<S> {
+    source: S,
+    delay: Duration,
+}
+
+impl<S> Delayed<S> {
+    pub fn new(source: S, delay: Duration) -> Self {
+        Delayed { source, delay }
+    }
+}
+
+impl<S: BlockSource> BlockSource for Delayed<S> {
+    fn get<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            self.source.get(cid).await
+        })
+    }
+}
@@ -1,15 +1,468 @@
-use minicbor::Encode;
-use serde_json::Value as JsonValue;
-use std::collections::BTreeMap;
+use crate::{Cid, CidDecoder};
+use ::cid::Cid as ExtCid;
+use anyhow::{anyhow, Result};
+use libipld::Ipld;
+use minicbor::{
+    data::{Tag, Type},
+    decode, Encode,
+};
+use multibase::Base;
+use serde_json::{Map, Number, Value as JsonValue};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+/// Deepest nesting of arrays and maps a decoded block may have, so a hostile block cannot
+/// overflow the stack. serde_json stops at the same depth when parsing.
+pub(crate) const MAX_DEPTH: usize = 128;
+
+/// A JSON record, with links and bytes represented following the DAG-JSON conventions
+/// of `{"/": "<cid>"}` and `{"/": {"bytes": "<base64>"}}`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Value(pub JsonValue);
 
+/// A borrowed `Value`, so a parsed record can be encoded without copying any of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRef<'a>(pub &'a JsonValue);
+
+/// How a `Value` is written as dag-cbor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Strict DAG-CBOR: map keys ordered by length and then bytewise, minimal integers and
+    /// 64 bit floats, so records get the same CID as with other IPLD implementations.
+    /// DAG-JSON link and bytes objects are written as tag 42 links and byte strings.
+    Strict,
+    /// The original encoding, with map keys in plain lexicographic order and no special
+    /// handling of `"/"`. Only needed to reproduce the CIDs of blocks written before strict
+    /// encoding was added.
+    Legacy,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Encoding::Strict),
+            "legacy" => Ok(Encoding::Legacy),
+            _ => Err(format!("Unknown encoding {}, expected strict or legacy", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Strict => "strict",
+            Encoding::Legacy => "legacy",
+        })
+    }
+}
+
+/// What strict encoding does with JSON numbers that are neither a 64 bit integer nor
+/// exactly representable as an f64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberPolicy {
+    /// Round to the nearest f64, as the legacy encoding always has
+    Float,
+    /// Write integers as CBOR bignums (tags 2 and 3) and round decimals to the nearest f64.
+    /// Bignums are not part of DAG-CBOR, so other implementations may reject these blocks.
+    Bignum,
+    /// Keep the number as a string of its JSON text
+    String,
+    /// Fail to encode the record
+    Reject,
+}
+
+impl FromStr for NumberPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "float" => Ok(NumberPolicy::Float),
+            "bignum" => Ok(NumberPolicy::Bignum),
+            "string" => Ok(NumberPolicy::String),
+            "reject" => Ok(NumberPolicy::Reject),
+            _ => Err(format!(
+                "Unknown number policy {}, expected float, bignum, string or reject",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NumberPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NumberPolicy::Float => "float",
+            NumberPolicy::Bignum => "bignum",
+            NumberPolicy::String => "string",
+            NumberPolicy::Reject => "reject",
+        })
+    }
+}
+
+/// Counts of the numbers a `NumberPolicy` applied to, shared by every record in a build
+#[derive(Debug, Default)]
+pub struct NumberStats {
+    rounded: AtomicU64,
+    bignums: AtomicU64,
+    strings: AtomicU64,
+}
+
+impl NumberStats {
+    /// Numbers that lost precision by being rounded to an f64
+    pub fn rounded(&self) -> u64 {
+        self.rounded.load(Ordering::Relaxed)
+    }
+
+    pub fn bignums(&self) -> u64 {
+        self.bignums.load(Ordering::Relaxed)
+    }
+
+    pub fn strings(&self) -> u64 {
+        self.strings.load(Ordering::Relaxed)
+    }
+}
+
+impl Value {
+    /// Decode a dag-cbor record block, links and bytes follow the DAG-JSON conventions.
+    pub fn from_dag_cbor(block: &[u8]) -> Result<Self> {
+        Ok(Value(decode_block(block, &mut vec![])?))
+    }
+
+    /// Like `from_dag_cbor`, but fails on any link `cids` does not accept
+    pub fn from_dag_cbor_with(block: &[u8], cids: &CidDecoder) -> Result<Self> {
+        let mut links = vec![];
+        let json = decode_block(block, &mut links)?;
+        for cid in &links {
+            cids.check(cid)
+                .map_err(|e| anyhow!("Link {}: {}", cid, e))?;
+        }
+        Ok(Value(json))
+    }
+
+    pub fn by_ref(&self) -> ValueRef<'_> {
+        ValueRef(&self.0)
+    }
+
+    /// See `ValueRef::to_dag_cbor`
+    pub fn to_dag_cbor(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        self.by_ref().to_dag_cbor(encoding)
+    }
+
+    /// See `ValueRef::to_dag_cbor_with`
+    pub fn to_dag_cbor_with(
+        &self,
+        encoding: Encoding,
+        numbers: NumberPolicy,
+        stats: &NumberStats,
+    ) -> Result<Vec<u8>> {
+        self.by_ref().to_dag_cbor_with(encoding, numbers, stats)
+    }
+}
+
+impl ValueRef<'_> {
+    /// Encode the record as a dag-cbor block, rounding numbers that do not fit to an f64.
+    pub fn to_dag_cbor(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        self.to_dag_cbor_with(encoding, NumberPolicy::Float, &NumberStats::default())
+    }
+
+    /// Encode the record as a dag-cbor block, applying `numbers` to any number that is not
+    /// exactly an integer or an f64. The legacy encoding ignores `numbers` and always rounds.
+    pub fn to_dag_cbor_with(
+        &self,
+        encoding: Encoding,
+        numbers: NumberPolicy,
+        stats: &NumberStats,
+    ) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Strict => {
+                let mut out = vec![];
+                encode_strict(self.0, &mut out, numbers, stats, &mut vec![])?;
+                Ok(out)
+            }
+            Encoding::Legacy => Ok(minicbor::to_vec(self)?),
+        }
+    }
+}
+
+/// A step on the way from the root of a record to a value, for error messages
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+fn path_string(path: &[Segment]) -> String {
+    let mut string = String::from("$");
+    for segment in path {
+        match segment {
+            Segment::Key(key) => string.push_str(&format!("[{:?}]", key)),
+            Segment::Index(index) => string.push_str(&format!("[{}]", index)),
+        }
+    }
+    string
+}
+
+/// minicbor picks the shortest encoding of integers and lengths, and is only given 64 bit
+/// floats, so everything it writes here is valid DAG-CBOR
+pub(crate) fn encoder(out: &mut Vec<u8>) -> minicbor::Encoder<&mut Vec<u8>> {
+    minicbor::Encoder::new(out)
+}
+
+fn encode_strict<'a>(
+    value: &'a JsonValue,
+    out: &mut Vec<u8>,
+    numbers: NumberPolicy,
+    stats: &NumberStats,
+    path: &mut Vec<Segment<'a>>,
+) -> Result<()> {
+    match value {
+        JsonValue::Null => {
+            encoder(out).null()?;
+        }
+        JsonValue::Bool(x) => {
+            encoder(out).bool(*x)?;
+        }
+        JsonValue::Number(x) => encode_number(x, out, numbers, stats, path)?,
+        JsonValue::String(x) => {
+            encoder(out).str(x)?;
+        }
+        JsonValue::Array(x) => {
+            encoder(out).array(x.len() as u64)?;
+            for (index, value) in x.iter().enumerate() {
+                path.push(Segment::Index(index));
+                encode_strict(value, out, numbers, stats, path)?;
+                path.pop();
+            }
+        }
+        JsonValue::Object(x) => match special(x) {
+            Some(Special::Link(cid)) => {
+                let cid = ExtCid::try_from(cid).map_err(|_| {
+                    anyhow!("Invalid CID in DAG-JSON link at {}", path_string(path))
+                })?;
+                encoder(out).encode(Cid(cid))?;
+            }
+            Some(Special::Bytes(bytes)) => {
+                let bytes = Base::Base64.decode(bytes).map_err(|_| {
+                    anyhow!("Invalid base64 in DAG-JSON bytes at {}", path_string(path))
+                })?;
+                encoder(out).bytes(&bytes)?;
+            }
+            None => {
+                let mut entries: Vec<(&String, &JsonValue)> = x.iter().collect();
+                entries.sort_unstable_by(|(a, _), (b, _)| {
+                    a.len()
+                        .cmp(&b.len())
+                        .then_with(|| a.as_bytes().cmp(b.as_bytes()))
+                });
+
+                encoder(out).map(entries.len() as u64)?;
+                for (key, value) in entries {
+                    encoder(out).str(key)?;
+                    path.push(Segment::Key(key));
+                    encode_strict(value, out, numbers, stats, path)?;
+                    path.pop();
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// minicbor already picks the shortest integer encoding and always writes f64 as 64 bits,
+/// but cannot write integers below `i64::MIN`, which DAG-CBOR allows down to -2^64
+fn encode_number(
+    x: &Number,
+    out: &mut Vec<u8>,
+    numbers: NumberPolicy,
+    stats: &NumberStats,
+    path: &[Segment],
+) -> Result<()> {
+    if let Some(x) = x.as_u64() {
+        encoder(out).u64(x)?;
+        return Ok(());
+    }
+    if let Some(x) = x.as_i64() {
+        encoder(out).i64(x)?;
+        return Ok(());
+    }
+
+    // With the arbitrary-precision feature this is the number exactly as written in the JSON
+    let text = x.to_string();
+    if let Some(n) = text
+        .parse::<i128>()
+        .ok()
+        .and_then(|x| u64::try_from(-1 - x).ok())
+    {
+        // Anything that fits was written by `i64` above, so this always takes 8 bytes
+        out.push(0x3b);
+        out.extend_from_slice(&n.to_be_bytes());
+        return Ok(());
+    }
+
+    let float = x.as_f64().filter(|f| f.is_finite());
+    let integer = !text.contains(['.', 'e', 'E']);
+    if !integer {
+        if let Some(f) = float.filter(|f| same_decimal(&text, &format!("{:e}", f))) {
+            encoder(out).f64(f)?;
+            return Ok(());
+        }
+    }
+
+    match (numbers, float) {
+        (NumberPolicy::Bignum, _) if integer => {
+            let (tag, magnitude) = match text.strip_prefix('-') {
+                // Negative bignums hold -1 - n
+                Some(digits) => (3, decrement(decimal_to_bytes(digits))),
+                None => (2, decimal_to_bytes(&text)),
+            };
+            encoder(out).tag(Tag::Unassigned(tag))?.bytes(&magnitude)?;
+            stats.bignums.fetch_add(1, Ordering::Relaxed);
+        }
+        (NumberPolicy::String, _) => {
+            encoder(out).str(&text)?;
+            stats.strings.fetch_add(1, Ordering::Relaxed);
+        }
+        (NumberPolicy::Float | NumberPolicy::Bignum, Some(f)) => {
+            encoder(out).f64(f)?;
+            stats.rounded.fetch_add(1, Ordering::Relaxed);
+        }
+        (NumberPolicy::Float | NumberPolicy::Bignum, None) => {
+            return Err(anyhow!(
+                "Number {} at {} is too large for an f64",
+                text,
+                path_string(path)
+            ))
+        }
+        (NumberPolicy::Reject, _) => {
+            return Err(anyhow!(
+                "Number {} at {} cannot be encoded without losing precision",
+                text,
+                path_string(path)
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Whether two decimal strings, possibly in exponent notation, are the same number
+fn same_decimal(a: &str, b: &str) -> bool {
+    normalize_decimal(a) == normalize_decimal(b)
+}
+
+/// The sign, significant digits and exponent of a decimal string
+fn normalize_decimal(text: &str) -> Option<(bool, String, i64)> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(i) => (&text[..i], text[i + 1..].parse::<i64>().ok()?),
+        None => (text, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let digits = format!("{}{}", whole, fraction);
+    let mut exponent = exponent - fraction.len() as i64;
+    let digits = digits.trim_start_matches('0');
+    let trimmed = digits.trim_end_matches('0');
+    exponent += (digits.len() - trimmed.len()) as i64;
+
+    match trimmed.is_empty() {
+        // All zeros are the same number
+        true => Some((false, String::new(), 0)),
+        false => Some((negative, trimmed.to_string(), exponent)),
+    }
+}
+
+/// Big-endian bytes of a string of decimal digits
+fn decimal_to_bytes(digits: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![];
+    for digit in digits.bytes() {
+        let mut carry = (digit - b'0') as u32;
+        for byte in bytes.iter_mut().rev() {
+            let value = *byte as u32 * 10 + carry;
+            *byte = value as u8;
+            carry = value >> 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    bytes
+}
+
+/// Decimal digits of big-endian bytes
+fn bytes_to_decimal(bytes: &[u8]) -> String {
+    let mut bytes = bytes.to_vec();
+    let mut digits = vec![];
+    while bytes.iter().any(|b| *b != 0) {
+        let mut remainder = 0u32;
+        for byte in bytes.iter_mut() {
+            let value = (remainder << 8) | *byte as u32;
+            *byte = (value / 10) as u8;
+            remainder = value % 10;
+        }
+        digits.push(b'0' + remainder as u8);
+    }
+    if digits.is_empty() {
+        digits.push(b'0');
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// Subtract one from a non-zero big-endian number
+fn decrement(mut bytes: Vec<u8>) -> Vec<u8> {
+    for byte in bytes.iter_mut().rev() {
+        let (value, borrow) = byte.overflowing_sub(1);
+        *byte = value;
+        if !borrow {
+            break;
+        }
+    }
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    bytes.split_off(zeros)
+}
+
+/// Add one to a big-endian number
+fn increment(mut bytes: Vec<u8>) -> Vec<u8> {
+    for byte in bytes.iter_mut().rev() {
+        let (value, carry) = byte.overflowing_add(1);
+        *byte = value;
+        if !carry {
+            return bytes;
+        }
+    }
+    bytes.insert(0, 1);
+    bytes
+}
+
+/// The legacy encoding, see `Encoding::Legacy`
 impl Encode for Value {
     fn encode<W: minicbor::encode::Write>(
         &self,
         e: &mut minicbor::Encoder<W>,
     ) -> Result<(), minicbor::encode::Error<W::Error>> {
-        match &self.0 {
+        self.by_ref().encode(e)
+    }
+
+    fn is_nil(&self) -> bool {
+        self.0 == JsonValue::Null
+    }
+}
+
+/// The legacy encoding, see `Encoding::Legacy`
+impl Encode for ValueRef<'_> {
+    fn encode<W: minicbor::encode::Write>(
+        &self,
+        e: &mut minicbor::Encoder<W>,
+    ) -> Result<(), minicbor::encode::Error<W::Error>> {
+        match self.0 {
             JsonValue::Null => e.null().map(|_| ()),
             JsonValue::Bool(x) => x.encode(e),
             JsonValue::Number(x) => {
@@ -18,24 +471,476 @@ impl Encode for Value {
                 } else if x.is_i64() {
                     x.as_i64().unwrap().encode(e)
                 } else {
-                    x.as_f64().unwrap().encode(e)
+                    x.as_f64()
+                        .ok_or(minicbor::encode::Error::Message(
+                            "Number is too large for an f64",
+                        ))?
+                        .encode(e)
                 }
             }
             JsonValue::String(x) => x.encode(e),
-            JsonValue::Array(x) => x
-                .iter()
-                .map(|x| Value(x.clone()))
-                .collect::<Vec<Value>>()
-                .encode(e),
-            JsonValue::Object(x) => x
-                .iter()
-                .map(|(s, v)| (s.clone(), Value(v.clone())))
-                .collect::<BTreeMap<String, Value>>()
-                .encode(e),
+            JsonValue::Array(x) => {
+                e.array(x.len() as u64)?;
+                for value in x {
+                    ValueRef(value).encode(e)?;
+                }
+                Ok(())
+            }
+            JsonValue::Object(x) => {
+                // serde_json already keeps keys sorted unless preserve_order is enabled
+                let mut entries: Vec<(&String, &JsonValue)> = x.iter().collect();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+
+                e.map(entries.len() as u64)?;
+                for (key, value) in entries {
+                    e.str(key)?;
+                    ValueRef(value).encode(e)?;
+                }
+                Ok(())
+            }
         }
     }
 
     fn is_nil(&self) -> bool {
-        self.0 == JsonValue::Null
+        self.0.is_null()
+    }
+}
+
+/// Decode the value `block` holds as JSON, failing if anything is left after it
+fn decode_block(block: &[u8], links: &mut Vec<Cid>) -> Result<JsonValue> {
+    let mut d = minicbor::Decoder::new(block);
+    let json = decode_json(&mut d, block, links, 0)?;
+    match block.len() - d.position() {
+        0 => Ok(json),
+        left => Err(anyhow!(
+            "Block has {} bytes left over after the value",
+            left
+        )),
+    }
+}
+
+/// Decode a value of `block` as JSON, adding every link in it to `links`. `depth` is the
+/// number of arrays and maps the value is inside.
+fn decode_json(
+    d: &mut minicbor::Decoder<'_>,
+    block: &[u8],
+    links: &mut Vec<Cid>,
+    depth: usize,
+) -> Result<JsonValue, decode::Error> {
+    if depth > MAX_DEPTH {
+        return Err(decode::Error::Message("Value is nested too deeply"));
+    }
+
+    match d.datatype()? {
+        Type::Null => d.null().map(|_| JsonValue::Null),
+        Type::Bool => d.bool().map(JsonValue::Bool),
+        Type::U8 | Type::U16 | Type::U32 | Type::U64 => d.u64().map(JsonValue::from),
+        Type::I8 | Type::I16 | Type::I32 => d.i64().map(JsonValue::from),
+        Type::I64 => {
+            // minicbor cannot read integers below i64::MIN, which DAG-CBOR allows
+            let position = d.position();
+            let argument = block
+                .get(position + 1..position + 9)
+                .ok_or(decode::Error::EndOfInput)?;
+            d.set_position(position + 9);
+            let n = u64::from_be_bytes(argument.try_into().unwrap());
+            Ok(match i64::try_from(n) {
+                Ok(n) => JsonValue::from(-1 - n),
+                Err(_) => {
+                    JsonValue::Number(Number::from_str(&(-1 - n as i128).to_string()).unwrap())
+                }
+            })
+        }
+        // DAG-CBOR only allows 64 bit floats
+        Type::F16 | Type::F32 => Err(decode::Error::Message("Floats must be encoded as 64 bit")),
+        Type::F64 => {
+            let x = d.f64()?;
+            Number::from_f64(x)
+                .map(JsonValue::Number)
+                .ok_or(decode::Error::Message(
+                    "NaN and infinite floats are not JSON",
+                ))
+        }
+        Type::String => d.str().map(JsonValue::from),
+        Type::Bytes => Ok(bytes_json(d.bytes()?)),
+        Type::Array => {
+            let length = d
+                .array()?
+                .ok_or(decode::Error::Message("Array must have a definite length"))?;
+            (0..length)
+                .map(|_| decode_json(d, block, links, depth + 1))
+                .collect::<Result<_, _>>()
+                .map(JsonValue::Array)
+        }
+        Type::Map => {
+            let length = d
+                .map()?
+                .ok_or(decode::Error::Message("Map must have a definite length"))?;
+            let mut map = Map::new();
+            for _ in 0..length {
+                let key = d.str()?.to_string();
+                map.insert(key, decode_json(d, block, links, depth + 1)?);
+            }
+            Ok(JsonValue::Object(map))
+        }
+        Type::Tag => match d.probe().tag()? {
+            Tag::Unassigned(42) => {
+                let cid: Cid = d.decode()?;
+                let json = link_json(&cid.0);
+                links.push(cid);
+                Ok(json)
+            }
+            // Bignums written by `NumberPolicy::Bignum`
+            Tag::PosBignum | Tag::NegBignum => {
+                let tag = d.tag()?;
+                let magnitude = d.bytes()?;
+                let text = match tag {
+                    Tag::PosBignum => bytes_to_decimal(magnitude),
+                    _ => format!("-{}", bytes_to_decimal(&increment(magnitude.to_vec()))),
+                };
+                Number::from_str(&text)
+                    .map(JsonValue::Number)
+                    .map_err(|_| decode::Error::Message("Invalid bignum"))
+            }
+            _ => Err(decode::Error::Message("Only tag 42 is allowed in dag-cbor")),
+        },
+        _ => Err(decode::Error::Message("Type is not allowed in dag-cbor")),
+    }
+}
+
+/// `{"/": "<cid>"}`
+fn link_json(cid: &ExtCid) -> JsonValue {
+    serde_json::json!({ "/": cid.to_string() })
+}
+
+/// `{"/": {"bytes": "<base64>"}}`, using unpadded standard base64
+fn bytes_json(bytes: &[u8]) -> JsonValue {
+    serde_json::json!({ "/": { "bytes": Base::Base64.encode(bytes) } })
+}
+
+/// The contents of a DAG-JSON link or bytes object
+enum Special<'a> {
+    Link(&'a str),
+    Bytes(&'a str),
+}
+
+/// Recognize the DAG-JSON `{"/": ...}` forms, anything else with a `"/"` key is a plain map
+fn special(map: &Map<String, JsonValue>) -> Option<Special<'_>> {
+    if map.len() != 1 {
+        return None;
+    }
+
+    match map.get("/")? {
+        JsonValue::String(cid) => Some(Special::Link(cid)),
+        JsonValue::Object(inner) if inner.len() == 1 => match inner.get("bytes")? {
+            JsonValue::String(bytes) => Some(Special::Bytes(bytes)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl From<JsonValue> for Value {
+    fn from(json: JsonValue) -> Self {
+        Value(json)
+    }
+}
+
+impl From<Value> for JsonValue {
+    fn from(value: Value) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<&Value> for Ipld {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        json_to_ipld(&value.0)
+    }
+}
+
+fn json_to_ipld(json: &JsonValue) -> Result<Ipld> {
+    Ok(match json {
+        JsonValue::Null => Ipld::Null,
+        JsonValue::Bool(x) => Ipld::Bool(*x),
+        JsonValue::Number(x) => match x.to_string().parse::<i128>() {
+            Ok(x) => Ipld::Integer(x),
+            Err(_) => Ipld::Float(
+                x.as_f64()
+                    .ok_or_else(|| anyhow!("Number {} does not fit in an f64", x))?,
+            ),
+        },
+        JsonValue::String(x) => Ipld::String(x.clone()),
+        JsonValue::Array(x) => Ipld::List(x.iter().map(json_to_ipld).collect::<Result<_>>()?),
+        JsonValue::Object(x) => match special(x) {
+            Some(Special::Link(cid)) => Ipld::Link(ExtCid::try_from(cid)?),
+            Some(Special::Bytes(bytes)) => Ipld::Bytes(Base::Base64.decode(bytes)?),
+            None => Ipld::StringMap(
+                x.iter()
+                    .map(|(k, v)| Ok((k.clone(), json_to_ipld(v)?)))
+                    .collect::<Result<_>>()?,
+            ),
+        },
+    })
+}
+
+impl TryFrom<&Ipld> for Value {
+    type Error = anyhow::Error;
+
+    fn try_from(ipld: &Ipld) -> Result<Self> {
+        ipld_to_json(ipld).map(Value)
+    }
+}
+
+fn ipld_to_json(ipld: &Ipld) -> Result<JsonValue> {
+    Ok(match ipld {
+        Ipld::Null => JsonValue::Null,
+        Ipld::Bool(x) => JsonValue::Bool(*x),
+        Ipld::Integer(x) => JsonValue::Number(Number::from_str(&x.to_string())?),
+        Ipld::Float(x) => JsonValue::Number(
+            Number::from_f64(*x).ok_or_else(|| anyhow!("Float {} does not fit in JSON", x))?,
+        ),
+        Ipld::String(x) => JsonValue::String(x.clone()),
+        Ipld::Bytes(x) => bytes_json(x),
+        Ipld::List(x) => JsonValue::Array(x.iter().map(ipld_to_json).collect::<Result<_>>()?),
+        Ipld::StringMap(x) => JsonValue::Object(
+            x.iter()
+                .map(|(k, v)| Ok((k.clone(), ipld_to_json(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+        Ipld::Link(cid) => link_json(cid),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{cbor::DagCborCodec, codec::Codec, ipld};
+    use multihash::{Code, MultihashDigest};
+    use serde_json::json;
+
+    fn strict(json: JsonValue) -> Vec<u8> {
+        Value(json).to_dag_cbor(Encoding::Strict).unwrap()
+    }
+
+    #[test]
+    fn strict_keys_are_ordered_by_length_then_bytes() {
+        let block = strict(json!({"bb": 1, "a": 2, "aa": 3, "b": 4, "B": 5}));
+        let mut d = minicbor::Decoder::new(&block);
+        assert_eq!(d.map().unwrap(), Some(5));
+        let mut keys = vec![];
+        for _ in 0..5 {
+            keys.push(d.str().unwrap().to_string());
+            d.skip().unwrap();
+        }
+        assert_eq!(keys, ["B", "a", "b", "aa", "bb"]);
+    }
+
+    #[test]
+    fn legacy_keys_are_ordered_lexicographically() {
+        let block = Value(json!({"bb": 1, "a": 2, "aa": 3}))
+            .to_dag_cbor(Encoding::Legacy)
+            .unwrap();
+        let mut d = minicbor::Decoder::new(&block);
+        d.map().unwrap();
+        let mut keys = vec![];
+        for _ in 0..3 {
+            keys.push(d.str().unwrap().to_string());
+            d.skip().unwrap();
+        }
+        assert_eq!(keys, ["a", "aa", "bb"]);
+    }
+
+    #[test]
+    fn borrowed_values_encode_like_libipld() {
+        let json = json!({
+            "title": "record",
+            "count": 3,
+            "negative": -7,
+            "tags": ["a", null, true],
+            "nested": {"b": [1, [2]], "a": {}},
+        });
+        let expected = ipld!({
+            "title": "record",
+            "count": 3,
+            "negative": -7,
+            "tags": ["a", null, true],
+            "nested": {"b": [1, [2]], "a": {}},
+        });
+
+        // libipld also orders map keys lexicographically, but it shrinks floats so there are none
+        let block = ValueRef(&json).to_dag_cbor(Encoding::Legacy).unwrap();
+        assert_eq!(block, DagCborCodec.encode(&expected).unwrap());
+        assert_eq!(
+            block,
+            Value(json.clone()).to_dag_cbor(Encoding::Legacy).unwrap()
+        );
+        assert_eq!(
+            ValueRef(&json).to_dag_cbor(Encoding::Strict).unwrap(),
+            strict(json)
+        );
+    }
+
+    #[test]
+    fn dag_cbor_round_trip() {
+        let value = json!({
+            "name": "record",
+            "count": 3,
+            "negative": -7,
+            "ratio": 0.5,
+            "tags": ["a", null, true],
+            "nested": {"inner": [1, [2, [3]]]},
+        });
+        let decoded = Value::from_dag_cbor(&strict(value.clone())).unwrap();
+        assert_eq!(decoded.0, value);
+    }
+
+    #[test]
+    fn dag_json_links_and_bytes_are_native() {
+        let cid = ExtCid::new_v1(0x71, Code::Sha2_256.digest(b"record"));
+        let link = json!({ "/": cid.to_string() });
+        let bytes = json!({"/": {"bytes": "AQID"}});
+
+        let mut expected = vec![0xd8, 0x2a, 0x58, 0x25, 0x00];
+        expected.extend(cid.to_bytes());
+        assert_eq!(strict(link.clone()), expected);
+        assert_eq!(strict(bytes.clone()), [0x43, 1, 2, 3]);
+
+        let record = json!({"link": link, "bytes": bytes});
+        assert_eq!(
+            Value::from_dag_cbor(&strict(record.clone())).unwrap().0,
+            record
+        );
+
+        // A "/" key alongside others is a plain map, on its own it has to be a valid CID
+        let plain = json!({"/": "not a cid", "other": 1});
+        assert!(Value(json!({"/": "not a cid"}))
+            .to_dag_cbor(Encoding::Strict)
+            .is_err());
+        assert_eq!(
+            Value::from_dag_cbor(&strict(plain.clone())).unwrap().0,
+            plain
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let nested = |depth: usize| {
+            let mut block = vec![0x81; depth];
+            block.push(0x00);
+            block
+        };
+        assert!(Value::from_dag_cbor(&nested(MAX_DEPTH)).is_ok());
+        assert!(Value::from_dag_cbor(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Value::from_dag_cbor(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn short_floats_are_rejected() {
+        // 1.5 as an f16, an f32 and an f64
+        assert!(Value::from_dag_cbor(&[0xf9, 0x3e, 0x00]).is_err());
+        assert!(Value::from_dag_cbor(&[0xfa, 0x3f, 0xc0, 0x00, 0x00]).is_err());
+        let block = [&[0xfb][..], &1.5f64.to_be_bytes()].concat();
+        assert_eq!(Value::from_dag_cbor(&block).unwrap().0, json!(1.5));
+    }
+
+    fn number(text: &str, numbers: NumberPolicy) -> (Result<Vec<u8>>, NumberStats) {
+        let stats = NumberStats::default();
+        let value = Value(serde_json::from_str(text).unwrap());
+        (
+            value.to_dag_cbor_with(Encoding::Strict, numbers, &stats),
+            stats,
+        )
+    }
+
+    fn head(major: u8, argument: u64) -> Vec<u8> {
+        let mut block = vec![major << 5 | 27];
+        block.extend_from_slice(&argument.to_be_bytes());
+        block
+    }
+
+    #[test]
+    fn integers_at_the_edges_are_native() {
+        let mut cases = vec![
+            ("18446744073709551615", head(0, u64::MAX)),
+            ("-9223372036854775808", head(1, i64::MAX as u64)),
+        ];
+        // Below i64::MIN serde_json only keeps the exact text with arbitrary precision
+        if cfg!(feature = "arbitrary-precision") {
+            cases.push(("-9223372036854775809", head(1, 1 << 63)));
+            cases.push(("-18446744073709551616", head(1, u64::MAX)));
+        }
+        for (text, expected) in cases {
+            for numbers in [
+                NumberPolicy::Float,
+                NumberPolicy::Bignum,
+                NumberPolicy::String,
+                NumberPolicy::Reject,
+            ] {
+                let (block, stats) = number(text, numbers);
+                assert_eq!(block.unwrap(), expected, "{} with {}", text, numbers);
+                assert_eq!(stats.rounded() + stats.bignums() + stats.strings(), 0);
+            }
+            let decoded = Value::from_dag_cbor(&expected).unwrap();
+            assert_eq!(decoded.0.to_string(), text);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "arbitrary-precision")]
+    fn number_policies_past_the_edges() {
+        for text in ["18446744073709551616", "-18446744073709551617"] {
+            let (block, stats) = number(text, NumberPolicy::Float);
+            assert_eq!(block.unwrap()[0], 0xfb);
+            assert_eq!(stats.rounded(), 1);
+
+            let (block, stats) = number(text, NumberPolicy::Bignum);
+            let block = block.unwrap();
+            assert_eq!(block[0], if text.starts_with('-') { 0xc3 } else { 0xc2 });
+            assert_eq!(stats.bignums(), 1);
+            assert_eq!(Value::from_dag_cbor(&block).unwrap().0.to_string(), text);
+
+            let (block, stats) = number(text, NumberPolicy::String);
+            assert_eq!(
+                Value::from_dag_cbor(&block.unwrap()).unwrap().0,
+                JsonValue::String(text.to_string())
+            );
+            assert_eq!(stats.strings(), 1);
+
+            assert!(number(text, NumberPolicy::Reject).0.is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "arbitrary-precision")]
+    fn number_policies_for_decimals() {
+        let (block, stats) = number("0.5", NumberPolicy::Reject);
+        assert_eq!(
+            block.unwrap(),
+            [&[0xfb][..], &0.5f64.to_be_bytes()].concat()
+        );
+        assert_eq!(stats.rounded(), 0);
+
+        let inexact = "0.10000000000000000000001";
+        let (block, stats) = number(inexact, NumberPolicy::Float);
+        assert_eq!(
+            block.unwrap(),
+            [&[0xfb][..], &0.1f64.to_be_bytes()].concat()
+        );
+        assert_eq!(stats.rounded(), 1);
+        let (_, stats) = number(inexact, NumberPolicy::Bignum);
+        assert_eq!(stats.rounded(), 1);
+        assert!(number(inexact, NumberPolicy::Reject).0.is_err());
+
+        assert!(number("1e400", NumberPolicy::Float).0.is_err());
+        assert!(number("1e400", NumberPolicy::String).0.is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "arbitrary-precision")]
+    fn legacy_rejects_numbers_too_large_for_an_f64() {
+        let value = Value(serde_json::from_str(r#"{"x": 1e400}"#).unwrap());
+        assert!(value.to_dag_cbor(Encoding::Legacy).is_err());
     }
 }